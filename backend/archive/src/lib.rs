@@ -0,0 +1,87 @@
+pub mod state;
+pub mod types;
+
+use crate::state::{archive_end, get_archive_config, BLOCKS, CONFIG, MAX_BLOCKS_PER_RESPONSE};
+use crate::types::{ArchiveConfig, BlockWithId, GetBlocksArgs, GetBlocksResult, Value};
+use ic_cdk::{init, query, update};
+
+#[init]
+pub fn init(arg: ArchiveConfig) {
+    CONFIG.with(|c| {
+        let mut c = c.borrow_mut();
+
+        c.set(arg).unwrap();
+    });
+}
+
+/// ======== Query ========
+
+#[query]
+pub fn remaining_capacity() -> u64 {
+    let config = get_archive_config();
+
+    config
+        .max_blocks
+        .saturating_sub(archive_end() - config.start)
+}
+
+#[query]
+pub fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let config = get_archive_config();
+    let end = archive_end();
+    let mut budget = MAX_BLOCKS_PER_RESPONSE;
+    let mut blocks = vec![];
+
+    BLOCKS.with(|log| {
+        let log = log.borrow();
+
+        for arg in args {
+            let start = arg.start.clamp(config.start as u128, end as u128) as u64;
+            let stop = arg.start.saturating_add(arg.length).min(end as u128) as u64;
+            let stop = stop.max(start).min(start + budget);
+
+            for (id, block) in log.range(start..stop) {
+                blocks.push(BlockWithId {
+                    id: id as u128,
+                    block,
+                });
+            }
+
+            budget -= stop - start;
+        }
+    });
+
+    GetBlocksResult {
+        log_length: end as u128,
+        blocks,
+        archived_blocks: vec![],
+    }
+}
+
+/// ======== Update ========
+
+#[update]
+pub fn append_blocks(blocks: Vec<Value>) {
+    let config = get_archive_config();
+
+    if ic_cdk::caller() != config.ledger_id {
+        ic_cdk::trap("Unauthorized");
+    }
+
+    let mut index = archive_end();
+
+    if index - config.start + blocks.len() as u64 > config.max_blocks {
+        ic_cdk::trap("Archive Full");
+    }
+
+    BLOCKS.with(|log| {
+        let mut log = log.borrow_mut();
+
+        for block in blocks {
+            log.insert(index, block);
+            index += 1;
+        }
+    });
+}
+
+ic_cdk::export_candid!();
@@ -0,0 +1,47 @@
+use crate::types::{ArchiveConfig, Value};
+use b3_utils::memory::{
+    init_stable_mem_refcell,
+    types::{Bound, DefaultStableBTreeMap, DefaultStableCell, Storable},
+};
+use candid::{Decode, Encode};
+use std::cell::RefCell;
+
+pub const MAX_BLOCKS_PER_RESPONSE: u64 = 1000;
+
+thread_local! {
+    pub static CONFIG: RefCell<DefaultStableCell<ArchiveConfig>> = init_stable_mem_refcell("config", 1).unwrap();
+    pub static BLOCKS: RefCell<DefaultStableBTreeMap<u64, Value>> = init_stable_mem_refcell("blocks", 2).unwrap();
+}
+
+impl Storable for ArchiveConfig {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Value {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+pub fn get_archive_config() -> ArchiveConfig {
+    CONFIG.with(|c| c.borrow().get().clone())
+}
+
+/// Index one past the last block held by this archive.
+pub fn archive_end() -> u64 {
+    get_archive_config().start + BLOCKS.with(|blocks| blocks.borrow().len())
+}
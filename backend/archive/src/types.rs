@@ -0,0 +1,60 @@
+use candid::{CandidType, Int, Nat, Principal};
+use serde::Deserialize;
+use serde_bytes::ByteBuf;
+
+/// ICRC-3 generic value, blocks are stored exactly as the ledger hashed them.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum Value {
+    Blob(ByteBuf),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct ArchiveConfig {
+    /// Ledger allowed to append blocks.
+    pub ledger_id: Principal,
+    /// Index of the first block this archive holds.
+    pub start: u64,
+    pub max_blocks: u64,
+}
+
+impl Default for ArchiveConfig {
+    fn default() -> Self {
+        Self {
+            ledger_id: Principal::anonymous(),
+            start: 0,
+            max_blocks: 0,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: u128,
+    pub length: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: u128,
+    pub block: Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksCallback,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: u128,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
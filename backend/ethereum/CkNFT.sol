@@ -110,12 +110,10 @@ contract CkNFT is
     emit SelfMint(msgid);
   }
 
-  function burnToCkNFT(uint256 id, bytes32 accountId) public whenNotPaused {
-    _burn(_msgSender(), id, 1);
-
-    emit BurnToCkNFT(id, accountId, 0);
-  }
-
+  /**
+   * @dev `principal` is the length byte of the principal followed by its bytes, zero
+   * padded, and `subaccount` is zero for the default one, as decoded by the canister.
+   */
   function burnToCkNFT(
     uint256 id,
     bytes32 principal,
@@ -0,0 +1,315 @@
+use crate::{
+    certification,
+    state::{insert_token, set_state_version, state_version, Token},
+    state::{Asset, AssetChunkKey, ASSETS, ASSET_CHUNKS, NEXT_ASSET_ID, PENDING_UPLOADS, TOKENS},
+    types::{AssetError, AssetInfo, CommitAssetUploadArg, PutAssetChunkArg},
+};
+use b3_utils::ledger::raw_sha256;
+use candid::Principal;
+use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+
+/// Leaves room for the rest of the ingress message below the 2MB limit.
+pub const MAX_CHUNK_SIZE: u64 = 1_900_000;
+
+pub const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+/// State version from which token images live in the asset store.
+const TOKEN_IMAGES_VERSION: u64 = 1;
+
+/// Nanoseconds an upload may stay uncommitted before it is removed with its chunks.
+pub const UPLOAD_EXPIRY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+pub fn begin_upload(owner: Principal, content_type: String, now: u64) -> u64 {
+    let asset_id = new_asset(owner, content_type);
+
+    PENDING_UPLOADS.with(|pending| pending.borrow_mut().insert(asset_id, now));
+
+    asset_id
+}
+
+fn new_asset(owner: Principal, content_type: String) -> u64 {
+    let asset_id = NEXT_ASSET_ID.with(|id| {
+        let mut id = id.borrow_mut();
+        let asset_id = *id.get();
+        id.set(asset_id + 1).unwrap();
+
+        asset_id
+    });
+
+    let asset = Asset {
+        owner,
+        content_type,
+        length: 0,
+        chunk_count: 0,
+        sha256: None,
+    };
+
+    ASSETS.with(|assets| assets.borrow_mut().insert(asset_id, asset));
+
+    asset_id
+}
+
+/// Returns the asset still being uploaded by `owner`.
+fn pending_upload(owner: &Principal, asset_id: u64) -> Result<Asset, AssetError> {
+    let asset = ASSETS
+        .with(|assets| assets.borrow().get(&asset_id))
+        .ok_or(AssetError::AssetNotFound)?;
+
+    if asset.owner != *owner {
+        return Err(AssetError::Unauthorized);
+    }
+
+    if asset.sha256.is_some() {
+        return Err(AssetError::AlreadyCommitted);
+    }
+
+    Ok(asset)
+}
+
+pub fn put_chunk(owner: &Principal, arg: PutAssetChunkArg) -> Result<(), AssetError> {
+    pending_upload(owner, arg.asset_id)?;
+
+    if arg.content.len() as u64 > MAX_CHUNK_SIZE {
+        return Err(AssetError::ChunkTooLarge {
+            max_chunk_size: MAX_CHUNK_SIZE,
+        });
+    }
+
+    let key = AssetChunkKey {
+        asset_id: arg.asset_id,
+        index: arg.index,
+    };
+
+    ASSET_CHUNKS.with(|chunks| chunks.borrow_mut().insert(key, arg.content.into_vec()));
+
+    Ok(())
+}
+
+/// Checks that every chunk is present and that their concatenation matches `sha256`.
+pub fn commit_upload(
+    owner: &Principal,
+    arg: CommitAssetUploadArg,
+) -> Result<AssetInfo, AssetError> {
+    let asset = pending_upload(owner, arg.asset_id)?;
+
+    let mut hasher = Sha256::new();
+    let mut length = 0;
+
+    for index in 0..arg.chunk_count {
+        match asset_chunk(arg.asset_id, index) {
+            Some(chunk) => {
+                hasher.update(&chunk);
+                length += chunk.len() as u64;
+            }
+            None => return Err(AssetError::MissingChunk { index }),
+        }
+    }
+
+    let sha256 = ByteBuf::from(hasher.finalize().to_vec());
+
+    if sha256 != arg.sha256 {
+        return Err(AssetError::HashMismatch { sha256 });
+    }
+
+    // chunks put past `chunk_count` are not part of the asset
+    remove_chunks(arg.asset_id, arg.chunk_count);
+
+    let asset = Asset {
+        length,
+        chunk_count: arg.chunk_count,
+        sha256: Some(sha256),
+        ..asset
+    };
+
+    ASSETS.with(|assets| assets.borrow_mut().insert(arg.asset_id, asset.clone()));
+    PENDING_UPLOADS.with(|pending| pending.borrow_mut().remove(&arg.asset_id));
+    certification::asset_changed(arg.asset_id);
+
+    Ok(info(arg.asset_id, asset))
+}
+
+/// Stores `content` as a committed single chunk asset.
+pub fn create_asset(owner: Principal, content_type: String, content: Vec<u8>) -> u64 {
+    let asset_id = new_asset(owner, content_type);
+    let sha256 = ByteBuf::from(raw_sha256(&content));
+    let length = content.len() as u64;
+
+    let key = AssetChunkKey { asset_id, index: 0 };
+    ASSET_CHUNKS.with(|chunks| chunks.borrow_mut().insert(key, content));
+
+    ASSETS.with(|assets| {
+        let mut assets = assets.borrow_mut();
+        let asset = assets.get(&asset_id).unwrap();
+
+        assets.insert(
+            asset_id,
+            Asset {
+                length,
+                chunk_count: 1,
+                sha256: Some(sha256),
+                ..asset
+            },
+        )
+    });
+    certification::asset_changed(asset_id);
+
+    asset_id
+}
+
+/// Removes the uploads begun more than `UPLOAD_EXPIRY` ago and never committed.
+pub fn prune_stale_uploads(now: u64) {
+    let stale: Vec<u64> = PENDING_UPLOADS.with(|pending| {
+        pending
+            .borrow()
+            .iter()
+            .filter(|(_, began)| began.saturating_add(UPLOAD_EXPIRY) <= now)
+            .map(|(asset_id, _)| asset_id)
+            .collect()
+    });
+
+    for asset_id in stale {
+        remove_chunks(asset_id, 0);
+        ASSETS.with(|assets| assets.borrow_mut().remove(&asset_id));
+        PENDING_UPLOADS.with(|pending| pending.borrow_mut().remove(&asset_id));
+    }
+}
+
+fn remove_chunks(asset_id: u64, from: u32) {
+    ASSET_CHUNKS.with(|chunks| {
+        let mut chunks = chunks.borrow_mut();
+
+        let stale: Vec<AssetChunkKey> = chunks
+            .range(
+                AssetChunkKey {
+                    asset_id,
+                    index: from,
+                }..,
+            )
+            .take_while(|(key, _)| key.asset_id == asset_id)
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in stale {
+            chunks.remove(&key);
+        }
+    });
+}
+
+fn info(asset_id: u64, asset: Asset) -> AssetInfo {
+    AssetInfo {
+        asset_id,
+        content_type: asset.content_type,
+        length: asset.length,
+        chunk_count: asset.chunk_count,
+        sha256: asset.sha256,
+    }
+}
+
+pub fn asset_info(asset_id: u64) -> Option<AssetInfo> {
+    ASSETS
+        .with(|assets| assets.borrow().get(&asset_id))
+        .map(|asset| info(asset_id, asset))
+}
+
+/// Whether `owner` uploaded the asset and committed it, tokens only reference assets of the
+/// minter that uploaded them.
+pub fn is_committed_by(asset_id: u64, owner: &Principal) -> bool {
+    ASSETS
+        .with(|assets| assets.borrow().get(&asset_id))
+        .is_some_and(|asset| asset.owner == *owner && asset.sha256.is_some())
+}
+
+pub fn asset_chunk(asset_id: u64, index: u32) -> Option<ByteBuf> {
+    ASSET_CHUNKS
+        .with(|chunks| chunks.borrow().get(&AssetChunkKey { asset_id, index }))
+        .map(ByteBuf::from)
+}
+
+/// Moves the inline images of tokens minted before the asset store into it, once.
+pub fn migrate_token_images(owner: Principal) {
+    if state_version() >= TOKEN_IMAGES_VERSION {
+        return;
+    }
+
+    let legacy: Vec<Token> = TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .iter()
+            .filter(|(_, token)| token.image.is_some())
+            .map(|(_, token)| token)
+            .collect()
+    });
+
+    for mut token in legacy {
+        let image = token.image.take().unwrap();
+        token.asset_id = Some(create_asset(owner, DEFAULT_CONTENT_TYPE.into(), image));
+
+        certification::token_changed(token.id);
+        insert_token(token);
+    }
+
+    set_state_version(TOKEN_IMAGES_VERSION);
+}
+
+#[test]
+fn test_chunked_upload_checks_hash_and_chunks() {
+    let owner = Principal::from_slice(&[8; 29]);
+    let asset_id = begin_upload(owner, "image/png".into(), 0);
+
+    let chunk = |index: u32, content: &[u8]| PutAssetChunkArg {
+        asset_id,
+        index,
+        content: ByteBuf::from(content.to_vec()),
+    };
+    let commit = |chunk_count: u32, content: &[u8]| CommitAssetUploadArg {
+        asset_id,
+        chunk_count,
+        sha256: ByteBuf::from(raw_sha256(content)),
+    };
+
+    assert_eq!(
+        put_chunk(&Principal::anonymous(), chunk(0, b"ab")),
+        Err(AssetError::Unauthorized)
+    );
+    put_chunk(&owner, chunk(0, b"ab")).unwrap();
+    put_chunk(&owner, chunk(2, b"ef")).unwrap();
+
+    assert_eq!(
+        commit_upload(&owner, commit(3, b"abcdef")),
+        Err(AssetError::MissingChunk { index: 1 })
+    );
+
+    put_chunk(&owner, chunk(1, b"cd")).unwrap();
+    assert!(matches!(
+        commit_upload(&owner, commit(3, b"abcdeX")),
+        Err(AssetError::HashMismatch { .. })
+    ));
+
+    let info = commit_upload(&owner, commit(3, b"abcdef")).unwrap();
+    assert_eq!((info.length, info.chunk_count), (6, 3));
+    assert!(is_committed_by(asset_id, &owner));
+    assert!(!is_committed_by(asset_id, &Principal::anonymous()));
+    assert_eq!(
+        put_chunk(&owner, chunk(3, b"gh")),
+        Err(AssetError::AlreadyCommitted)
+    );
+    let stale = begin_upload(owner, "image/png".into(), 0);
+    put_chunk(
+        &owner,
+        PutAssetChunkArg {
+            asset_id: stale,
+            index: 0,
+            content: ByteBuf::from(b"ab".to_vec()),
+        },
+    )
+    .unwrap();
+
+    prune_stale_uploads(UPLOAD_EXPIRY - 1);
+    assert!(asset_info(stale).is_some());
+
+    prune_stale_uploads(UPLOAD_EXPIRY);
+    assert!(asset_info(stale).is_none());
+    assert!(asset_chunk(stale, 0).is_none());
+    assert!(is_committed_by(asset_id, &owner));
+}
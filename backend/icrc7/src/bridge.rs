@@ -0,0 +1,367 @@
+use crate::{
+    evm_rpc::{EvmLog, EvmRpc, LogFilter},
+    types::{MintState, MintStatus, WithdrawError},
+};
+use b3_utils::{ledger::raw_keccak256, ledger::ICRCAccount, Subaccount};
+use candid::Principal;
+
+/// Blocks searched per `eth_getLogs` request, providers reject wider ranges.
+const LOG_RANGE_BLOCKS: u64 = 500;
+
+/// `eth_getLogs` requests of one check, a check that falls behind catches up on the next.
+pub const LOG_RANGES_PER_CHECK: u64 = 5;
+
+/// Seconds past `expiry` before an unsigned request is expired or a mint is attested as
+/// expired, EVM block times and provider lag make the last valid blocks show up late.
+pub const EXPIRY_GRACE_PERIOD: u64 = 15 * 60;
+
+/// A token burned on EVM, to be released from the custody account to `to`.
+#[derive(Clone, Debug, PartialEq)]
+pub struct BurnEvent {
+    pub log_index: u64,
+    pub token_id: u128,
+    pub to: ICRCAccount,
+}
+
+/// The `BurnToCkNFT` logs emitted by the CkNFT contract in `tx_hash`, once the transaction
+/// has `min_confirmations` blocks on top of it.
+pub async fn confirmed_burn_events(
+    rpc: &impl EvmRpc,
+    cknft_eth_address: &str,
+    min_confirmations: u64,
+    tx_hash: &str,
+) -> Result<Vec<BurnEvent>, WithdrawError> {
+    let receipt = rpc
+        .get_transaction_receipt(tx_hash)
+        .await
+        .map_err(WithdrawError::RpcError)?
+        .ok_or(WithdrawError::TransactionNotFound)?;
+
+    if !receipt.success {
+        return Err(WithdrawError::TransactionFailed);
+    }
+
+    let head = rpc
+        .finalized_block()
+        .await
+        .map_err(WithdrawError::RpcError)?;
+    let confirmations = (head.number + 1).saturating_sub(receipt.block_number);
+
+    if confirmations < min_confirmations {
+        return Err(WithdrawError::NotConfirmed {
+            confirmations,
+            required: min_confirmations,
+        });
+    }
+
+    let events: Vec<BurnEvent> = receipt
+        .logs
+        .iter()
+        .filter_map(|log| parse_burn_event(log, cknft_eth_address))
+        .collect();
+
+    if events.is_empty() {
+        return Err(WithdrawError::NoBurnEvent);
+    }
+
+    Ok(events)
+}
+
+/// `BurnToCkNFT(uint256 id, bytes32 indexed principal, bytes32 indexed subaccount)`, logs of
+/// other contracts or events are ignored, as are burns to an undecodable principal.
+pub fn parse_burn_event(log: &EvmLog, cknft_eth_address: &str) -> Option<BurnEvent> {
+    if !log.address.eq_ignore_ascii_case(cknft_eth_address) {
+        return None;
+    }
+
+    let topic = raw_keccak256(b"BurnToCkNFT(uint256,bytes32,bytes32)").to_vec();
+
+    let (principal, subaccount): (&[u8; 32], &[u8; 32]) = match log.topics.as_slice() {
+        [event, principal, subaccount] if *event == topic => (
+            principal.as_slice().try_into().ok()?,
+            subaccount.as_slice().try_into().ok()?,
+        ),
+        _ => return None,
+    };
+
+    // uint256 id, ids past u128 were never bridged out
+    if log.data.len() != 32 || log.data[..16].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+    let token_id = u128::from_be_bytes(log.data[16..].try_into().unwrap());
+
+    let subaccount = (*subaccount != [0; 32]).then_some(Subaccount(*subaccount));
+
+    Some(BurnEvent {
+        log_index: log.log_index,
+        token_id,
+        to: ICRCAccount::new(decode_principal(principal)?, subaccount),
+    })
+}
+
+/// Principals are packed as their length byte followed by their bytes, zero padded.
+fn decode_principal(bytes: &[u8; 32]) -> Option<Principal> {
+    let length = bytes[0] as usize;
+
+    if length == 0 || length > 29 || bytes[1 + length..].iter().any(|byte| *byte != 0) {
+        return None;
+    }
+
+    Principal::try_from_slice(&bytes[1..1 + length])
+        .ok()
+        .filter(|principal| *principal != Principal::anonymous())
+}
+
+/// `abi.encode(id, to, msgid, expiry, block.chainid, address(this))` as hashed and checked by
+/// `selfMint`, 32 bytes per argument.
+pub fn self_mint_payload(
+    id: u128,
+    to: &[u8; 20],
+    msgid: u128,
+    expiry: u64,
+    chain_id: u64,
+    cknft_eth_address: &[u8; 20],
+) -> [u8; 192] {
+    let mut payload = [0; 192];
+    payload[16..32].copy_from_slice(&id.to_be_bytes());
+    payload[44..64].copy_from_slice(to);
+    payload[80..96].copy_from_slice(&msgid.to_be_bytes());
+    payload[120..128].copy_from_slice(&expiry.to_be_bytes());
+    payload[152..160].copy_from_slice(&chain_id.to_be_bytes());
+    payload[172..192].copy_from_slice(cknft_eth_address);
+    payload
+}
+
+/// Next state of a pending entry, `Confirmed` once `SelfMint(msgid)` shows up in the logs of
+/// the CkNFT contract and `Expired` when its signature can no longer be used. The finalized
+/// blocks from `from_block` on are searched in ranges, `from_block` records the progress.
+pub async fn check_mint_status(
+    rpc: &impl EvmRpc,
+    cknft_eth_address: &str,
+    msgid: u128,
+    status: &MintStatus,
+    now_secs: u64,
+) -> Result<MintStatus, String> {
+    // without a signature nothing can have been minted on EVM
+    if status.state != MintState::Signed {
+        let expired = now_secs >= status.expiry.saturating_add(EXPIRY_GRACE_PERIOD);
+
+        return Ok(MintStatus {
+            state: if expired {
+                MintState::Expired
+            } else {
+                status.state.clone()
+            },
+            ..status.clone()
+        });
+    }
+
+    // entries signed without a start block are left to `attest_mint_expired`
+    let mut from_block = match status.from_block {
+        Some(from_block) => from_block,
+        None => return Ok(status.clone()),
+    };
+
+    let head = rpc.finalized_block().await?;
+
+    for _ in 0..LOG_RANGES_PER_CHECK {
+        if from_block > head.number {
+            break;
+        }
+
+        let filter = LogFilter {
+            address: cknft_eth_address.into(),
+            topics: vec![
+                raw_keccak256(b"SelfMint(uint256)").to_vec(),
+                [[0; 16], msgid.to_be_bytes()].concat(),
+            ],
+            from_block,
+            to_block: head.number.min(from_block + LOG_RANGE_BLOCKS - 1),
+        };
+
+        if !rpc.get_logs(&filter).await?.is_empty() {
+            return Ok(MintStatus {
+                state: MintState::Confirmed,
+                from_block: Some(from_block),
+                ..status.clone()
+            });
+        }
+
+        from_block = filter.to_block + 1;
+    }
+
+    // `selfMint` requires `block.timestamp < expiry`, so no block after a searched one
+    // at or past expiry can hold the mint
+    let state = if from_block > head.number && head.timestamp >= status.expiry {
+        MintState::Expired
+    } else {
+        MintState::Signed
+    };
+
+    Ok(MintStatus {
+        state,
+        from_block: Some(from_block),
+        ..status.clone()
+    })
+}
+
+#[test]
+fn test_confirmed_burn_events_from_receipt() {
+    use crate::evm_rpc::{block_on, BlockHeader, MockEvmRpc, TransactionReceipt};
+
+    let address = "0xAbC0000000000000000000000000000000000001";
+    let owner = Principal::from_slice(&[7; 10]);
+
+    let mut principal = vec![0u8; 32];
+    principal[0] = 10;
+    principal[1..11].copy_from_slice(owner.as_slice());
+
+    let log = |address: &str, log_index: u64| EvmLog {
+        address: address.into(),
+        topics: vec![
+            raw_keccak256(b"BurnToCkNFT(uint256,bytes32,bytes32)").to_vec(),
+            principal.clone(),
+            vec![0; 32],
+        ],
+        data: [[0; 16], 42u128.to_be_bytes()].concat(),
+        block_number: 0x10,
+        log_index,
+    };
+
+    let mut rpc = MockEvmRpc {
+        head: BlockHeader {
+            number: 0x20,
+            timestamp: 0,
+        },
+        ..Default::default()
+    };
+    rpc.receipts.insert(
+        "0x01".into(),
+        TransactionReceipt {
+            block_number: 0x10,
+            success: true,
+            logs: vec![
+                log(address, 3),
+                log("0x9990000000000000000000000000000000000009", 4),
+            ],
+        },
+    );
+
+    let events = block_on(confirmed_burn_events(
+        &rpc,
+        &address.to_lowercase(),
+        12,
+        "0x01",
+    ))
+    .unwrap();
+    assert_eq!(
+        events,
+        vec![BurnEvent {
+            log_index: 3,
+            token_id: 42,
+            to: ICRCAccount::new(owner, None),
+        }]
+    );
+
+    rpc.head.number = 0x12;
+    assert!(matches!(
+        block_on(confirmed_burn_events(&rpc, address, 12, "0x01")),
+        Err(WithdrawError::NotConfirmed {
+            confirmations: 3,
+            required: 12
+        })
+    ));
+    assert!(matches!(
+        block_on(confirmed_burn_events(&rpc, address, 1, "0x02")),
+        Err(WithdrawError::TransactionNotFound)
+    ));
+}
+
+#[test]
+fn test_check_mint_status() {
+    use crate::evm_rpc::{block_on, BlockHeader, MockEvmRpc};
+
+    let address = "0xabc0000000000000000000000000000000000001";
+    let status = MintStatus {
+        id: 1,
+        amount: 1,
+        expiry: 1_000,
+        state: MintState::Signed,
+        owner: None,
+        chain_id: Some(1),
+        from_block: Some(2_000),
+        to: None,
+    };
+
+    let mut rpc = MockEvmRpc {
+        head: BlockHeader {
+            number: 5_000,
+            timestamp: 900,
+        },
+        ..Default::default()
+    };
+
+    // the range is searched over several checks
+    let checked = block_on(check_mint_status(&rpc, address, 7, &status, 2_000)).unwrap();
+    assert_eq!(checked.state, MintState::Signed);
+    assert_eq!(checked.from_block, Some(4_500));
+
+    // searched to the head, which is not past expiry yet
+    let checked = block_on(check_mint_status(&rpc, address, 7, &checked, 2_000)).unwrap();
+    assert_eq!(checked.state, MintState::Signed);
+    assert_eq!(checked.from_block, Some(5_001));
+
+    rpc.head.timestamp = 1_000;
+    let checked = block_on(check_mint_status(&rpc, address, 7, &checked, 2_000)).unwrap();
+    assert_eq!(checked.state, MintState::Expired);
+
+    let unknown_start = MintStatus {
+        from_block: None,
+        ..status.clone()
+    };
+    let checked = block_on(check_mint_status(&rpc, address, 7, &unknown_start, 2_000)).unwrap();
+    assert_eq!(checked.state, MintState::Signed);
+
+    rpc.logs.push(EvmLog {
+        address: address.into(),
+        topics: vec![
+            raw_keccak256(b"SelfMint(uint256)").to_vec(),
+            [[0; 16], 7u128.to_be_bytes()].concat(),
+        ],
+        data: vec![],
+        block_number: 2_100,
+        log_index: 0,
+    });
+    let confirmed = block_on(check_mint_status(&rpc, address, 7, &status, 2_000)).unwrap();
+    assert_eq!(confirmed.state, MintState::Confirmed);
+
+    let unsigned = MintStatus {
+        state: MintState::FundReceived,
+        ..status
+    };
+    let checked = block_on(check_mint_status(&rpc, address, 7, &unsigned, 2_000)).unwrap();
+    assert_eq!(checked.state, MintState::Expired);
+}
+
+#[test]
+fn test_self_mint_payload() {
+    let mut cknft_eth_address = [0; 20];
+    cknft_eth_address[0] = 0xab;
+    cknft_eth_address[1] = 0xc0;
+    cknft_eth_address[19] = 0x01;
+
+    let payload = self_mint_payload(
+        0x0123456789abcdef0123456789abcdef,
+        &[0x11; 20],
+        0xfedcba9876543210fedcba9876543210,
+        1_700_000_000,
+        11_155_111,
+        &cknft_eth_address,
+    );
+
+    // keccak256(abi.encode(...)) of the same arguments, computed off chain
+    assert_eq!(
+        b3_utils::vec_to_hex_string(raw_keccak256(&payload).to_vec()),
+        "6f91e5cb4a10fccb00a335c0baab0b4cf0b66c44a7ba4f5e23bb8cb8cbc2c788"
+    );
+}
@@ -0,0 +1,382 @@
+use crate::{
+    assets::asset_info,
+    http,
+    icrc3::tip_tree,
+    state::{get_icrc7_config, get_log_tip, ASSETS, TOKENS},
+    types::{HttpResponse, StreamingStrategy},
+};
+use b3_utils::http::HttpRequest;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use ic_certification::{fork, fork_hash, pruned, Hash};
+use ic_http_certification::{
+    DefaultCelBuilder, DefaultCelExpression, DefaultResponseCertification, HttpCertification,
+    HttpCertificationPath, HttpCertificationTree, HttpCertificationTreeEntry,
+    CERTIFICATE_EXPRESSION_HEADER_NAME, CERTIFICATE_HEADER_NAME,
+};
+use serde::Serialize;
+use serde_bytes::ByteBuf;
+use std::{
+    cell::{Cell, RefCell},
+    collections::{BTreeSet, HashMap},
+    time::Duration,
+};
+
+/// Tokens and assets certified by each round of a full pass, a token renders every one of its
+/// urls and its asset, which keeps a round well below the instruction limit.
+const TOKEN_BATCH_SIZE: usize = 50;
+const ASSET_BATCH_SIZE: usize = 10;
+
+thread_local! {
+    static HTTP_TREE: RefCell<HttpCertificationTree> = RefCell::default();
+    /// Entries certified for each url, kept to delete them once the response changes.
+    static CERTIFIED: RefCell<HashMap<String, Vec<HttpCertificationTreeEntry<'static>>>> = RefCell::default();
+    static PENDING_TOKENS: RefCell<BTreeSet<u128>> = RefCell::default();
+    static PENDING_ASSETS: RefCell<BTreeSet<u64>> = RefCell::default();
+    /// Next token and asset of the running full pass, `None` once they are all certified.
+    static TOKEN_CURSOR: Cell<Option<u128>> = const { Cell::new(None) };
+    static ASSET_CURSOR: Cell<Option<u64>> = const { Cell::new(None) };
+}
+
+/// Recertifies the routes of the token on the next `update_certified_data`.
+pub fn token_changed(id: u128) {
+    PENDING_TOKENS.with(|pending| pending.borrow_mut().insert(id));
+}
+
+pub fn asset_changed(asset_id: u64) {
+    PENDING_ASSETS.with(|pending| pending.borrow_mut().insert(asset_id));
+}
+
+/// The tree lives on the heap, it is rebuilt after an upgrade and whenever the
+/// collection metadata rendered into every response changes. The rebuild runs in timer
+/// rounds of bounded size, routes not reached yet are served without a certificate.
+pub fn certify_all() {
+    HTTP_TREE.with(|tree| *tree.borrow_mut() = HttpCertificationTree::default());
+    CERTIFIED.with(|certified| certified.borrow_mut().clear());
+
+    let running = TOKEN_CURSOR.get().is_some() || ASSET_CURSOR.get().is_some();
+
+    TOKEN_CURSOR.set(Some(0));
+    ASSET_CURSOR.set(Some(0));
+
+    update_certified_data();
+
+    // a running pass picks the reset cursors up
+    if !running {
+        ic_cdk_timers::set_timer(Duration::ZERO, certify_batch);
+    }
+}
+
+fn certify_batch() {
+    if let Some(from) = TOKEN_CURSOR.get() {
+        let ids: Vec<u128> = TOKENS.with(|tokens| {
+            tokens
+                .borrow()
+                .range(from..)
+                .take(TOKEN_BATCH_SIZE + 1)
+                .map(|(id, _)| id)
+                .collect()
+        });
+
+        TOKEN_CURSOR.set(ids.get(TOKEN_BATCH_SIZE).copied());
+        PENDING_TOKENS.with(|pending| {
+            pending
+                .borrow_mut()
+                .extend(ids.into_iter().take(TOKEN_BATCH_SIZE))
+        });
+    } else if let Some(from) = ASSET_CURSOR.get() {
+        let ids: Vec<u64> = ASSETS.with(|assets| {
+            assets
+                .borrow()
+                .range(from..)
+                .take(ASSET_BATCH_SIZE + 1)
+                .map(|(id, _)| id)
+                .collect()
+        });
+
+        ASSET_CURSOR.set(ids.get(ASSET_BATCH_SIZE).copied());
+        PENDING_ASSETS.with(|pending| {
+            pending
+                .borrow_mut()
+                .extend(ids.into_iter().take(ASSET_BATCH_SIZE))
+        });
+    }
+
+    update_certified_data();
+
+    if TOKEN_CURSOR.get().is_some() || ASSET_CURSOR.get().is_some() {
+        ic_cdk_timers::set_timer(Duration::ZERO, certify_batch);
+    }
+}
+
+/// Certifies the pending routes, then sets the certified data to the HTTP tree
+/// forked with the ICRC-3 tip.
+pub fn update_certified_data() {
+    // responses render the canister id and only canisters have certified data, so the
+    // ledger logic runs without it in unit tests
+    if !cfg!(target_arch = "wasm32") {
+        return;
+    }
+
+    let tokens = PENDING_TOKENS.with(|pending| pending.take());
+    let assets = PENDING_ASSETS.with(|pending| pending.take());
+    let chain_ids = get_icrc7_config().evm_chain_ids.unwrap_or_default();
+
+    for id in tokens {
+        let exists = TOKENS.with(|tokens| tokens.borrow().contains_key(&id));
+
+        for urls in token_urls(id, &chain_ids) {
+            if exists {
+                certify_urls(urls);
+            } else {
+                urls.iter().for_each(|url| uncertify_url(url));
+            }
+        }
+    }
+
+    for asset_id in assets {
+        certify_urls(vec![format!("/assets/{}", asset_id)]);
+    }
+
+    let root_hash = fork_hash(&http_root_hash(), &tip_tree(&get_log_tip()).digest());
+    ic_cdk::api::set_certified_data(&root_hash);
+}
+
+pub fn http_root_hash() -> Hash {
+    HTTP_TREE.with(|tree| tree.borrow().root_hash())
+}
+
+/// Adds the certificate headers, responses that are not in the tree are served as is.
+/// Only GET is certified, HEAD and OPTIONS carry no expression the certificate could back.
+pub fn certify_response(req: &HttpRequest, mut response: HttpResponse) -> HttpResponse {
+    if !req.method.eq_ignore_ascii_case("GET") {
+        return response;
+    }
+
+    let expression = match route_expression(req.path()) {
+        Some(expression) => expression,
+        None => return response,
+    };
+
+    response.headers.push((
+        CERTIFICATE_EXPRESSION_HEADER_NAME.into(),
+        expression.to_string(),
+    ));
+
+    let entry = match tree_entry(req, &response, &expression) {
+        Some(entry) => entry,
+        None => return response,
+    };
+
+    let certified = CERTIFIED.with(|certified| {
+        certified
+            .borrow()
+            .get(&req.url)
+            .is_some_and(|entries| entries.contains(&entry))
+    });
+
+    let certificate = match ic_cdk::api::data_certificate() {
+        Some(certificate) if certified => certificate,
+        _ => return response,
+    };
+
+    let witness = match HTTP_TREE.with(|tree| tree.borrow().witness(&entry, req.path())) {
+        Ok(witness) => witness,
+        Err(_) => return response,
+    };
+    let tree = fork(witness, pruned(tip_tree(&get_log_tip()).digest()));
+
+    response.headers.push((
+        CERTIFICATE_HEADER_NAME.into(),
+        format!(
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+            STANDARD.encode(certificate),
+            STANDARD.encode(cbor(&tree)),
+            STANDARD.encode(cbor(&entry.path.to_expr_path())),
+        ),
+    ));
+
+    response
+}
+
+/// CBOR with the self-describe tag, as expected for certificate trees.
+pub fn cbor<T: Serialize>(value: &T) -> Vec<u8> {
+    let mut serializer = serde_cbor::ser::Serializer::new(vec![]);
+    serializer.self_describe().unwrap();
+    value.serialize(&mut serializer).unwrap();
+
+    serializer.into_inner()
+}
+
+/// Urls of a token grouped by response, each group is rendered once.
+fn token_urls(id: u128, chain_ids: &[u64]) -> Vec<Vec<String>> {
+    let mut urls = vec![
+        vec![format!("/token?id={}", id), format!("/token/{}", id)],
+        vec![format!("/evm/{}", id), format!("/evm/{:064x}", id)],
+        vec![format!("/asset/{}", id)],
+    ];
+
+    for chain_id in chain_ids {
+        urls.push(vec![format!("/evm/cknft/{}/{}", chain_id, id)]);
+    }
+
+    urls
+}
+
+/// `/token?id=` certifies the request along for its query parameter, the other routes
+/// carry everything in the path.
+fn route_expression(path: &str) -> Option<DefaultCelExpression<'static>> {
+    let response_certification = DefaultResponseCertification::response_header_exclusions(vec![]);
+
+    if path == "/token" {
+        return Some(DefaultCelExpression::Full(
+            DefaultCelBuilder::full_certification()
+                .with_request_query_parameters(vec!["id"])
+                .with_response_certification(response_certification)
+                .build(),
+        ));
+    }
+
+    ["/token/", "/evm/", "/asset/", "/assets/"]
+        .iter()
+        .any(|prefix| path.starts_with(prefix))
+        .then(|| {
+            DefaultCelExpression::ResponseOnly(
+                DefaultCelBuilder::response_only_certification()
+                    .with_response_certification(response_certification)
+                    .build(),
+            )
+        })
+}
+
+/// Certifies the current response of `urls`, which all render the same, plus the 304
+/// answered to revalidations.
+fn certify_urls(urls: Vec<String>) {
+    let get = |url: &str, etag: Option<&String>| HttpRequest {
+        method: "GET".into(),
+        url: url.into(),
+        headers: etag
+            .map(|etag| vec![("If-None-Match".into(), etag.clone())])
+            .unwrap_or_default(),
+        body: ByteBuf::new(),
+    };
+
+    let response = http::handle(&get(&urls[0], None));
+
+    let etag = response
+        .headers
+        .iter()
+        .find(|(name, _)| name == "ETag")
+        .map(|(_, etag)| etag);
+    let revalidation = etag.map(|etag| http::handle(&get(&urls[0], Some(etag))));
+
+    for url in urls.iter() {
+        let mut entries = vec![];
+
+        entries.extend(certified_entry(&get(url, None), response.clone()));
+        if let Some(revalidation) = &revalidation {
+            entries.extend(certified_entry(&get(url, etag), revalidation.clone()));
+        }
+
+        uncertify_url(url);
+
+        if entries.is_empty() {
+            continue;
+        }
+
+        HTTP_TREE.with(|tree| {
+            let mut tree = tree.borrow_mut();
+            entries.iter().for_each(|entry| tree.insert(entry));
+        });
+        CERTIFIED.with(|certified| certified.borrow_mut().insert(url.clone(), entries));
+    }
+}
+
+fn uncertify_url(url: &str) {
+    let entries = CERTIFIED.with(|certified| certified.borrow_mut().remove(url));
+
+    HTTP_TREE.with(|tree| {
+        let mut tree = tree.borrow_mut();
+        entries
+            .iter()
+            .flatten()
+            .for_each(|entry| tree.delete(entry));
+    });
+}
+
+/// The entry of `response` served for `req`, along with the expression header of its route.
+fn certified_entry(
+    req: &HttpRequest,
+    mut response: HttpResponse,
+) -> Option<HttpCertificationTreeEntry<'static>> {
+    let expression = route_expression(req.path())?;
+
+    response.headers.push((
+        CERTIFICATE_EXPRESSION_HEADER_NAME.into(),
+        expression.to_string(),
+    ));
+
+    tree_entry(req, &response, &expression)
+}
+
+/// Only successful responses are certified, a route missing from the tree serves uncertified.
+fn tree_entry(
+    req: &HttpRequest,
+    response: &HttpResponse,
+    expression: &DefaultCelExpression,
+) -> Option<HttpCertificationTreeEntry<'static>> {
+    if !matches!(response.status_code, 200 | 304) {
+        return None;
+    }
+
+    // streamed bodies are certified by the hash of the whole asset
+    let body_hash = match &response.streaming_strategy {
+        Some(StreamingStrategy::Callback { token, .. }) => asset_info(token.asset_id)
+            .and_then(|info| info.sha256)
+            .and_then(|sha256| sha256.into_vec().try_into().ok()),
+        None => None,
+    };
+
+    let http_response = ic_http_certification::HttpResponse {
+        status_code: response.status_code,
+        headers: response.headers.clone(),
+        body: response.body.to_vec(),
+        upgrade: None,
+    };
+
+    let certification = match expression {
+        DefaultCelExpression::Full(expression) => {
+            let http_request = ic_http_certification::HttpRequest {
+                method: req.method.clone(),
+                url: req.url.clone(),
+                headers: req.headers.clone(),
+                body: req.body.to_vec(),
+            };
+
+            HttpCertification::full(expression, &http_request, &http_response, body_hash)
+        }
+        DefaultCelExpression::ResponseOnly(expression) => {
+            HttpCertification::response_only(expression, &http_response, body_hash)
+        }
+        DefaultCelExpression::Skip => return None,
+    };
+
+    Some(HttpCertificationTreeEntry::new(
+        HttpCertificationPath::exact(req.path().to_string()),
+        certification.ok()?,
+    ))
+}
+
+#[test]
+fn test_token_urls_are_certified_routes() {
+    for url in token_urls(42, &[1, 137]).concat() {
+        let path = url.split('?').next().unwrap();
+        assert!(route_expression(path).is_some(), "{}", url);
+    }
+
+    assert!(matches!(
+        route_expression("/token"),
+        Some(DefaultCelExpression::Full(_))
+    ));
+    assert!(route_expression("/block").is_none());
+    assert!(route_expression("/partition_details").is_none());
+}
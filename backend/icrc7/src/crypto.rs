@@ -31,7 +31,7 @@ impl Storable for EcdsaSignature {
         }
     }
 
-    fn to_bytes(&self) -> Cow<[u8]> {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
         let mut bytes = Vec::with_capacity(65);
         bytes.extend_from_slice(&self.r);
         bytes.extend_from_slice(&self.s);
@@ -40,13 +40,13 @@ impl Storable for EcdsaSignature {
     }
 }
 
-impl std::string::ToString for EcdsaSignature {
-    fn to_string(&self) -> String {
+impl std::fmt::Display for EcdsaSignature {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let mut bytes = Vec::with_capacity(65);
         bytes.extend_from_slice(&self.r);
         bytes.extend_from_slice(&self.s);
         bytes.push(self.v);
-        vec_to_hex_string(&bytes)
+        write!(f, "{}", vec_to_hex_string(&bytes))
     }
 }
 
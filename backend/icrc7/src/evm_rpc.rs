@@ -0,0 +1,508 @@
+use crate::types::ConsensusStrategy;
+use b3_utils::{hex_string_with_0x_to_u64, hex_string_with_0x_to_vec, vec_to_hex_string_with_0x};
+use ic_cdk::api::management_canister::http_request::{
+    http_request, CanisterHttpRequestArgument, HttpHeader, HttpMethod, HttpResponse, TransformArgs,
+    TransformContext,
+};
+use serde_json::{json, Value};
+
+/// Receipts of bridge transactions carry a handful of logs, far below this.
+pub const MAX_RESPONSE_BYTES: u64 = 64_000;
+
+/// Blocks come with the hashes of their transactions, over a thousand on busy chains.
+pub const MAX_BLOCK_RESPONSE_BYTES: u64 = 256_000;
+
+fn max_response_bytes(method: &str) -> u64 {
+    match method {
+        "eth_getBlockByNumber" => MAX_BLOCK_RESPONSE_BYTES,
+        _ => MAX_RESPONSE_BYTES,
+    }
+}
+
+/// Fee of an outcall of `method` with a request of up to 1KB on a 34 node subnet, the
+/// unused part is refunded.
+pub fn outcall_cycles(method: &str) -> u128 {
+    const NODES: u128 = 34;
+
+    (3_000_000 + 60_000 * NODES) * NODES
+        + 400 * NODES * 1_000
+        + 800 * NODES * max_response_bytes(method) as u128
+}
+
+/// Reads of EVM state, through HTTPS outcalls to JSON-RPC providers or from memory in tests.
+#[allow(async_fn_in_trait)]
+pub trait EvmRpc {
+    /// Latest finalized block, it only moves once per finality round, so replicas querying
+    /// a few seconds apart still agree on it.
+    async fn finalized_block(&self) -> Result<BlockHeader, String>;
+
+    /// `None` while the transaction is pending or unknown to the providers.
+    async fn get_transaction_receipt(
+        &self,
+        tx_hash: &str,
+    ) -> Result<Option<TransactionReceipt>, String>;
+
+    async fn get_logs(&self, filter: &LogFilter) -> Result<Vec<EvmLog>, String>;
+}
+
+/// Transport of JSON-RPC requests, outcalls on the IC and canned responses in tests.
+#[allow(async_fn_in_trait)]
+pub trait JsonRpcProvider {
+    async fn request(&self, method: &str, body: String) -> Result<String, String>;
+}
+
+pub struct HttpsOutcallProvider {
+    pub url: String,
+}
+
+impl JsonRpcProvider for HttpsOutcallProvider {
+    async fn request(&self, method: &str, body: String) -> Result<String, String> {
+        let arg = CanisterHttpRequestArgument {
+            url: self.url.clone(),
+            max_response_bytes: Some(max_response_bytes(method)),
+            method: HttpMethod::POST,
+            headers: vec![HttpHeader {
+                name: "Content-Type".into(),
+                value: "application/json".into(),
+            }],
+            body: Some(body.into_bytes()),
+            transform: Some(TransformContext::from_name(
+                "transform_rpc_response".into(),
+                method.as_bytes().to_vec(),
+            )),
+        };
+
+        let (response,) = http_request(arg, outcall_cycles(method))
+            .await
+            .map_err(|(code, message)| format!("{:?}: {}", code, message))?;
+
+        if response.status != 200u16 {
+            return Err(format!("Provider answered {}", response.status));
+        }
+
+        String::from_utf8(response.body).map_err(|e| e.to_string())
+    }
+}
+
+/// Replicas have to agree on the response, so headers are dropped and the body is reduced
+/// to its `result` or `error`, re-serialized with sorted keys. The context is the method,
+/// its result is reduced to the fields read from it.
+pub fn transform_rpc_response(args: TransformArgs) -> HttpResponse {
+    let method = String::from_utf8_lossy(&args.context);
+
+    let body = match serde_json::from_slice::<Value>(&args.response.body) {
+        Ok(body) => {
+            let mut body = normalize_response(body);
+            if body["error"].is_null() {
+                body["result"] = normalize_result(&method, body["result"].take());
+            }
+
+            body.to_string().into_bytes()
+        }
+        Err(_) => vec![],
+    };
+
+    HttpResponse {
+        status: args.response.status,
+        headers: vec![],
+        body,
+    }
+}
+
+/// Drops the envelope fields that differ between providers and requests.
+fn normalize_response(mut body: Value) -> Value {
+    match body["error"].take() {
+        Value::Null => json!({ "result": body["result"].take() }),
+        error => json!({ "error": { "code": error["code"], "message": error["message"] } }),
+    }
+}
+
+/// Providers add fields of their own to blocks, receipts and logs (`effectiveGasPrice`, L2 fees,
+/// `blockTimestamp`), only those read here are compared.
+fn normalize_result(method: &str, result: Value) -> Value {
+    let log = |log: &Value| {
+        json!({
+            "address": log["address"].as_str().map(str::to_ascii_lowercase),
+            "topics": log["topics"],
+            "data": log["data"],
+            "blockNumber": log["blockNumber"],
+            "logIndex": log["logIndex"],
+        })
+    };
+    // logs of a reorganized block
+    let logs = |logs: &Value| -> Value {
+        logs.as_array()
+            .map(|logs| {
+                logs.iter()
+                    .filter(|l| l["removed"] != true)
+                    .map(log)
+                    .collect()
+            })
+            .unwrap_or(Value::Null)
+    };
+
+    match method {
+        "eth_getBlockByNumber" => json!({
+            "number": result["number"],
+            "timestamp": result["timestamp"],
+        }),
+        "eth_getTransactionReceipt" if !result.is_null() => json!({
+            "blockNumber": result["blockNumber"],
+            "status": result["status"],
+            "logs": logs(&result["logs"]),
+        }),
+        "eth_getLogs" => logs(&result),
+        _ => result,
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlockHeader {
+    pub number: u64,
+    /// Seconds since the epoch.
+    pub timestamp: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct EvmLog {
+    pub address: String,
+    pub topics: Vec<Vec<u8>>,
+    pub data: Vec<u8>,
+    pub block_number: u64,
+    pub log_index: u64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TransactionReceipt {
+    pub block_number: u64,
+    pub success: bool,
+    pub logs: Vec<EvmLog>,
+}
+
+/// `eth_getLogs` filter, `topics` match by position.
+#[derive(Clone, Debug)]
+pub struct LogFilter {
+    pub address: String,
+    pub topics: Vec<Vec<u8>>,
+    pub from_block: u64,
+    pub to_block: u64,
+}
+
+impl LogFilter {
+    pub fn matches(&self, log: &EvmLog) -> bool {
+        log.address.eq_ignore_ascii_case(&self.address)
+            && (self.from_block..=self.to_block).contains(&log.block_number)
+            && log.topics.len() >= self.topics.len()
+            && self.topics.iter().zip(&log.topics).all(|(a, b)| a == b)
+    }
+}
+
+/// Sends every request to all providers and only returns what enough of them agree on.
+pub struct MultiProviderRpc<P> {
+    providers: Vec<P>,
+    consensus: ConsensusStrategy,
+}
+
+pub type HttpsEvmRpc = MultiProviderRpc<HttpsOutcallProvider>;
+
+impl HttpsEvmRpc {
+    pub fn https(urls: Vec<String>, consensus: ConsensusStrategy) -> Self {
+        let providers = urls
+            .into_iter()
+            .map(|url| HttpsOutcallProvider { url })
+            .collect();
+
+        Self::new(providers, consensus)
+    }
+}
+
+impl<P: JsonRpcProvider> MultiProviderRpc<P> {
+    pub fn new(providers: Vec<P>, consensus: ConsensusStrategy) -> Self {
+        Self {
+            providers,
+            consensus,
+        }
+    }
+
+    /// Providers needed to agree on a result.
+    fn quorum(&self) -> usize {
+        match self.consensus {
+            ConsensusStrategy::Equality => self.providers.len(),
+            ConsensusStrategy::Threshold { min } => (min as usize).clamp(1, self.providers.len()),
+        }
+    }
+
+    async fn call_all(
+        &self,
+        method: &str,
+        params: Value,
+    ) -> Result<Vec<Result<Value, String>>, String> {
+        if self.providers.is_empty() {
+            return Err("No RPC providers".into());
+        }
+
+        let body = json!({ "jsonrpc": "2.0", "id": 1, "method": method, "params": params });
+
+        let mut results = Vec::with_capacity(self.providers.len());
+
+        for provider in &self.providers {
+            let result = call(provider, method, body.to_string()).await;
+            results.push(result.map(|result| normalize_result(method, result)));
+        }
+
+        Ok(results)
+    }
+
+    async fn call(&self, method: &str, params: Value) -> Result<Value, String> {
+        let results = self.call_all(method, params).await?;
+
+        consensus(results, self.quorum())
+    }
+}
+
+impl<P: JsonRpcProvider> EvmRpc for MultiProviderRpc<P> {
+    /// Providers see finalized blocks at different times, so this is the highest block that
+    /// at least a quorum of them has reached.
+    async fn finalized_block(&self) -> Result<BlockHeader, String> {
+        let mut heads = vec![];
+        let mut error = None;
+
+        let params = json!(["finalized", false]);
+        for result in self.call_all("eth_getBlockByNumber", params).await? {
+            match result.and_then(|head| parse_block_header(&head)) {
+                Ok(head) => heads.push(head),
+                Err(e) => error = Some(e),
+            }
+        }
+
+        heads.sort_unstable_by_key(|head| std::cmp::Reverse(head.number));
+
+        heads
+            .get(self.quorum() - 1)
+            .cloned()
+            .ok_or_else(|| error.unwrap_or_else(|| "Providers disagree".into()))
+    }
+
+    async fn get_transaction_receipt(
+        &self,
+        tx_hash: &str,
+    ) -> Result<Option<TransactionReceipt>, String> {
+        let receipt = self
+            .call("eth_getTransactionReceipt", json!([tx_hash]))
+            .await?;
+
+        if receipt.is_null() {
+            return Ok(None);
+        }
+
+        let logs = receipt["logs"]
+            .as_array()
+            .ok_or("Missing logs")?
+            .iter()
+            .map(parse_log)
+            .collect::<Result<_, String>>()?;
+
+        Ok(Some(TransactionReceipt {
+            block_number: quantity(&receipt["blockNumber"])?,
+            success: quantity(&receipt["status"])? == 1,
+            logs,
+        }))
+    }
+
+    async fn get_logs(&self, filter: &LogFilter) -> Result<Vec<EvmLog>, String> {
+        let topics: Vec<String> = filter
+            .topics
+            .iter()
+            .map(vec_to_hex_string_with_0x)
+            .collect();
+
+        let params = json!([{
+            "address": filter.address,
+            "topics": topics,
+            "fromBlock": format!("0x{:x}", filter.from_block),
+            "toBlock": format!("0x{:x}", filter.to_block),
+        }]);
+
+        self.call("eth_getLogs", params)
+            .await?
+            .as_array()
+            .ok_or("Expected logs")?
+            .iter()
+            .map(parse_log)
+            .collect()
+    }
+}
+
+async fn call<P: JsonRpcProvider>(
+    provider: &P,
+    method: &str,
+    body: String,
+) -> Result<Value, String> {
+    let response = provider.request(method, body).await?;
+    let response: Value = serde_json::from_str(&response).map_err(|e| e.to_string())?;
+
+    let mut response = normalize_response(response);
+
+    if !response["error"].is_null() {
+        return Err(response["error"].to_string());
+    }
+
+    Ok(response["result"].take())
+}
+
+/// The result returned by at least `quorum` providers, or the first error when none is.
+fn consensus(results: Vec<Result<Value, String>>, quorum: usize) -> Result<Value, String> {
+    let mut counts: Vec<(Value, usize)> = vec![];
+    let mut error = None;
+
+    for result in results {
+        match result {
+            Ok(value) => match counts.iter_mut().find(|(seen, _)| *seen == value) {
+                Some((_, count)) => *count += 1,
+                None => counts.push((value, 1)),
+            },
+            Err(e) => {
+                error.get_or_insert(e);
+            }
+        }
+    }
+
+    match counts.into_iter().find(|(_, count)| *count >= quorum) {
+        Some((value, _)) => Ok(value),
+        None => Err(error.unwrap_or_else(|| "Providers disagree".into())),
+    }
+}
+
+fn parse_block_header(block: &Value) -> Result<BlockHeader, String> {
+    Ok(BlockHeader {
+        number: quantity(&block["number"])?,
+        timestamp: quantity(&block["timestamp"])?,
+    })
+}
+
+fn parse_log(log: &Value) -> Result<EvmLog, String> {
+    Ok(EvmLog {
+        address: log["address"].as_str().ok_or("Missing address")?.into(),
+        topics: log["topics"]
+            .as_array()
+            .ok_or("Missing topics")?
+            .iter()
+            .map(bytes)
+            .collect::<Result<_, _>>()?,
+        data: bytes(&log["data"])?,
+        block_number: quantity(&log["blockNumber"])?,
+        log_index: quantity(&log["logIndex"])?,
+    })
+}
+
+fn quantity(value: &Value) -> Result<u64, String> {
+    hex_string_with_0x_to_u64(value.as_str().ok_or("Expected a quantity")?)
+        .map_err(|e| e.to_string())
+}
+
+fn bytes(value: &Value) -> Result<Vec<u8>, String> {
+    hex_string_with_0x_to_vec(value.as_str().ok_or("Expected hex data")?).map_err(|e| e.to_string())
+}
+
+/// In-memory chain for unit tests.
+#[cfg(test)]
+#[derive(Default)]
+pub struct MockEvmRpc {
+    pub head: BlockHeader,
+    pub receipts: std::collections::HashMap<String, TransactionReceipt>,
+    pub logs: Vec<EvmLog>,
+}
+
+#[cfg(test)]
+impl EvmRpc for MockEvmRpc {
+    async fn finalized_block(&self) -> Result<BlockHeader, String> {
+        Ok(self.head.clone())
+    }
+
+    async fn get_transaction_receipt(
+        &self,
+        tx_hash: &str,
+    ) -> Result<Option<TransactionReceipt>, String> {
+        Ok(self.receipts.get(tx_hash).cloned())
+    }
+
+    async fn get_logs(&self, filter: &LogFilter) -> Result<Vec<EvmLog>, String> {
+        Ok(self
+            .logs
+            .iter()
+            .filter(|log| filter.matches(log))
+            .cloned()
+            .collect())
+    }
+}
+
+/// Polls a future that never suspends, enough for the canned providers and mock of tests.
+#[cfg(test)]
+pub fn block_on<F: std::future::Future>(future: F) -> F::Output {
+    use std::task::{Context, Poll, Waker};
+
+    let mut context = Context::from_waker(Waker::noop());
+    match std::pin::pin!(future).poll(&mut context) {
+        Poll::Ready(output) => output,
+        Poll::Pending => panic!("test futures never pend"),
+    }
+}
+
+#[test]
+fn test_multi_provider_consensus() {
+    struct Canned(&'static str);
+
+    impl JsonRpcProvider for Canned {
+        async fn request(&self, method: &str, body: String) -> Result<String, String> {
+            let request: Value = serde_json::from_str(&body).unwrap();
+            assert_eq!(request["jsonrpc"], "2.0");
+            assert_eq!(request["method"], method);
+
+            Ok(format!(r#"{{"jsonrpc":"2.0","id":7,"result":{}}}"#, self.0))
+        }
+    }
+
+    let receipt = r#"{"blockNumber":"0x10","status":"0x1","logs":[]}"#;
+    // fields of one provider only are not compared
+    let with_fees = r#"{"blockNumber":"0x10","status":"0x1","logs":[],"effectiveGasPrice":"0x7"}"#;
+    let reverted = r#"{"blockNumber":"0x10","status":"0x0","logs":[]}"#;
+
+    let rpc = |results: [&'static str; 3], consensus| {
+        MultiProviderRpc::new(results.map(Canned).into(), consensus)
+    };
+    let majority = ConsensusStrategy::Threshold { min: 2 };
+
+    let agreeing = rpc([receipt, with_fees, receipt], ConsensusStrategy::Equality);
+    assert!(block_on(agreeing.get_transaction_receipt("0x01"))
+        .unwrap()
+        .is_some_and(|receipt| receipt.success && receipt.block_number == 16));
+
+    let split = rpc([receipt, receipt, reverted], ConsensusStrategy::Equality);
+    assert!(block_on(split.get_transaction_receipt("0x01")).is_err());
+
+    let split = rpc([receipt, reverted, receipt], majority.clone());
+    assert!(block_on(split.get_transaction_receipt("0x01"))
+        .unwrap()
+        .is_some_and(|receipt| receipt.success));
+
+    let heads = rpc(
+        [
+            r#"{"number":"0x12","timestamp":"0x66","hash":"0xaa"}"#,
+            r#"{"number":"0x10","timestamp":"0x42","hash":"0xbb"}"#,
+            r#"{"number":"0x11","timestamp":"0x54","hash":"0xcc"}"#,
+        ],
+        majority,
+    );
+    assert_eq!(
+        block_on(heads.finalized_block()),
+        Ok(BlockHeader {
+            number: 0x11,
+            timestamp: 0x54
+        })
+    );
+
+    assert_eq!(
+        normalize_response(json!({ "jsonrpc": "2.0", "id": 3, "result": "0x1" })),
+        normalize_response(json!({ "id": 9, "result": "0x1", "jsonrpc": "2.0" }))
+    );
+}
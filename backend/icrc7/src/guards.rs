@@ -0,0 +1,43 @@
+use crate::{
+    state::{has_role, is_paused},
+    types::Role,
+};
+
+fn caller_has_role(role: Role) -> Result<(), String> {
+    let caller = ic_cdk::caller();
+
+    if ic_cdk::api::is_controller(&caller) || has_role(&caller, role) {
+        Ok(())
+    } else {
+        Err(format!("Caller does not have the {:?} role", role))
+    }
+}
+
+pub fn caller_is_owner() -> Result<(), String> {
+    caller_has_role(Role::Owner)
+}
+
+pub fn caller_is_config_admin() -> Result<(), String> {
+    caller_has_role(Role::ConfigAdmin)
+}
+
+pub fn caller_is_minter() -> Result<(), String> {
+    caller_has_role(Role::Minter)
+}
+
+pub fn caller_is_pauser() -> Result<(), String> {
+    caller_has_role(Role::Pauser)
+}
+
+pub fn caller_is_bridge_operator() -> Result<(), String> {
+    caller_has_role(Role::BridgeOperator)
+}
+
+/// Transfers, mints, burns and bridge calls are rejected while the canister is paused.
+pub fn not_paused() -> Result<(), String> {
+    if is_paused() {
+        Err("Canister is paused".into())
+    } else {
+        Ok(())
+    }
+}
@@ -0,0 +1,421 @@
+use crate::{
+    assets::{asset_chunk, asset_info, DEFAULT_CONTENT_TYPE},
+    icrc3::get_block,
+    state::{get_icrc7_config, TOKENS},
+    types::{AssetInfo, StreamingCallbackHttpResponse},
+    types::{HttpResponse, StreamingCallback, StreamingCallbackToken, StreamingStrategy},
+};
+use b3_utils::memory::with_stable_mem;
+use b3_utils::{http::HttpRequest, http::HttpResponseBuilder, vec_to_hex_string};
+use serde::Serialize;
+use serde_json::json;
+use std::str::FromStr;
+
+/// Receives the request and the rest of the path after a prefix route.
+pub type Handler = fn(&HttpRequest, &str) -> HttpResponse;
+
+/// Matched in order, a route ending in `/` matches by prefix and hands the rest of the path over.
+pub const ROUTES: &[(&str, Handler)] = &[
+    ("/token", token_response),
+    ("/token/", token_response),
+    ("/block", block_response),
+    ("/block/", block_response),
+    ("/transfer_log", block_response),
+    ("/partition_details", partition_details_response),
+    ("/asset/", token_asset_response),
+    ("/assets/", asset_id_response),
+    ("/evm/", evm_metadata_response),
+];
+
+const ALLOWED_METHODS: &str = "GET, HEAD, OPTIONS";
+
+/// Dispatches through `ROUTES`, every response carries the CORS headers.
+pub fn handle(req: &HttpRequest) -> HttpResponse {
+    let mut response = match req.method.to_ascii_uppercase().as_str() {
+        "GET" => route(req),
+        "HEAD" => {
+            // same headers as GET, the Content-Length included
+            let mut response = route(req);
+            response.body = Default::default();
+            response.streaming_strategy = None;
+            response
+        }
+        "OPTIONS" => {
+            let mut response: HttpResponse = HttpResponseBuilder::ok()
+                .header("Access-Control-Allow-Methods", ALLOWED_METHODS)
+                .header(
+                    "Access-Control-Allow-Headers",
+                    "Content-Type, If-None-Match",
+                )
+                .header("Access-Control-Max-Age", 86400)
+                .build()
+                .into();
+            response.status_code = 204;
+            response
+        }
+        _ => {
+            let mut response = error_response(405, "Method not allowed");
+            response
+                .headers
+                .push(("Allow".into(), ALLOWED_METHODS.into()));
+            response
+        }
+    };
+
+    response
+        .headers
+        .push(("Access-Control-Allow-Origin".into(), "*".into()));
+
+    response
+}
+
+fn route(req: &HttpRequest) -> HttpResponse {
+    let path = req.path();
+
+    let found = ROUTES.iter().find_map(|(route, handler)| {
+        if route.ends_with('/') {
+            path.strip_prefix(route).map(|rest| (*handler, rest))
+        } else {
+            (path == *route).then_some((*handler, ""))
+        }
+    });
+
+    match found {
+        Some((handler, rest)) => handler(req, rest),
+        None => error_response(404, "Route not found"),
+    }
+}
+
+pub fn error_response(status_code: u16, message: impl ToString) -> HttpResponse {
+    let body = json!({ "code": status_code, "message": message.to_string() });
+
+    let mut response: HttpResponse = HttpResponseBuilder::ok()
+        .header("Content-Type", "application/json; charset=utf-8")
+        .with_body_and_content_length(body.to_string())
+        .build()
+        .into();
+    response.status_code = status_code;
+
+    response
+}
+
+fn json_response(value: &impl Serialize) -> HttpResponse {
+    HttpResponseBuilder::ok()
+        .header("Content-Type", "application/json; charset=utf-8")
+        .with_body_and_content_length(serde_json::to_string(value).unwrap_or_default())
+        .build()
+        .into()
+}
+
+/// The id of `/route/<id>`, or of `/route?id=<id>`.
+fn id_param<T: FromStr>(req: &HttpRequest, path_id: &str) -> Result<T, String> {
+    let id = if path_id.is_empty() {
+        req.raw_query_param("id")
+    } else {
+        Some(path_id)
+    };
+
+    match id {
+        None => Err("Missing id".into()),
+        Some(id) => id.parse().map_err(|_| format!("Invalid id {}", id)),
+    }
+}
+
+/// `/token?id=<id>` and `/token/<id>`.
+fn token_response(req: &HttpRequest, token_id: &str) -> HttpResponse {
+    let token_id: u128 = match id_param(req, token_id) {
+        Ok(id) => id,
+        Err(message) => return error_response(400, message),
+    };
+
+    match TOKENS.with(|tokens| tokens.borrow().get(&token_id)) {
+        Some(token) => json_response(&token),
+        None => error_response(404, "Token not found"),
+    }
+}
+
+/// `/block?id=<index>` and `/block/<index>`, `/transfer_log` is kept for older clients.
+fn block_response(req: &HttpRequest, index: &str) -> HttpResponse {
+    let index: u64 = match id_param(req, index) {
+        Ok(index) => index,
+        Err(message) => return error_response(400, message),
+    };
+
+    match get_block(index) {
+        Some(block) => json_response(&block),
+        None => error_response(404, "Block not found"),
+    }
+}
+
+fn partition_details_response(_: &HttpRequest, _: &str) -> HttpResponse {
+    json_response(&with_stable_mem(|pm| pm.partition_details()))
+}
+
+/// Assets are immutable once committed, so their own route may be cached forever.
+const ASSET_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+const TOKEN_ASSET_CACHE_CONTROL: &str = "public, max-age=86400";
+
+/// `/asset/<token_id>`, the asset referenced by a token.
+pub fn token_asset_response(req: &HttpRequest, token_id: &str) -> HttpResponse {
+    let token_id: u128 = match id_param(req, token_id) {
+        Ok(id) => id,
+        Err(message) => return error_response(400, message),
+    };
+
+    let asset_id = TOKENS
+        .with(|tokens| tokens.borrow().get(&token_id))
+        .and_then(|token| token.asset_id);
+
+    match asset_id {
+        Some(asset_id) => asset_response(req, asset_id, TOKEN_ASSET_CACHE_CONTROL),
+        None => error_response(404, "Asset not found"),
+    }
+}
+
+/// `/assets/<asset_id>`.
+pub fn asset_id_response(req: &HttpRequest, asset_id: &str) -> HttpResponse {
+    match id_param(req, asset_id) {
+        Ok(asset_id) => asset_response(req, asset_id, ASSET_CACHE_CONTROL),
+        Err(message) => error_response(400, message),
+    }
+}
+
+/// `/evm/<id>` and `/evm/cknft/<chain_id>/<id>`, ERC-1155 metadata of a token for the
+/// `uri` of CkNFT.sol. Ids may be decimal or the 64 hex digits of the ERC-1155 `{id}`.
+pub fn evm_metadata_response(_: &HttpRequest, route: &str) -> HttpResponse {
+    let parts: Vec<&str> = route.split('/').collect();
+
+    let (chain_id, token_id) = match parts.as_slice() {
+        [id] => (None, *id),
+        ["cknft", chain_id, id] => match chain_id.parse::<u64>() {
+            Ok(chain_id) => (Some(chain_id), *id),
+            Err(_) => return error_response(400, format!("Invalid chain id {}", chain_id)),
+        },
+        _ => return error_response(404, "Route not found"),
+    };
+
+    let token_id = match parse_evm_token_id(token_id) {
+        Some(id) => id,
+        None => return error_response(400, format!("Invalid id {}", token_id)),
+    };
+
+    let token = match TOKENS.with(|tokens| tokens.borrow().get(&token_id)) {
+        Some(token) => token,
+        None => return error_response(404, "Token not found"),
+    };
+
+    let config = get_icrc7_config();
+
+    let mut attributes = vec![
+        json!({ "trait_type": "Collection", "value": config.name() }),
+        json!({ "trait_type": "Token Id", "value": token.id.to_string() }),
+    ];
+    if let Some(chain_id) = chain_id {
+        attributes.push(json!({ "trait_type": "Chain Id", "value": chain_id }));
+    }
+
+    let mut metadata = json!({
+        "name": token.name,
+        "description": token.description.or(config.description()).unwrap_or_default(),
+        "external_url": format!("{}/token?id={}", canister_url(), token.id),
+        "attributes": attributes,
+    });
+    if token.asset_id.is_some() {
+        metadata["image"] = format!("{}/asset/{}", canister_url(), token.id).into();
+    }
+
+    json_response(&metadata)
+}
+
+fn parse_evm_token_id(id: &str) -> Option<u128> {
+    if id.len() == 64 {
+        u128::from_str_radix(id.trim_start_matches('0'), 16)
+            .ok()
+            .or(id.chars().all(|c| c == '0').then_some(0))
+    } else {
+        id.parse().ok()
+    }
+}
+
+/// Raw domain, EVM clients fetch metadata without the service worker.
+fn canister_url() -> String {
+    format!("https://{}.raw.icp0.io", ic_cdk::id())
+}
+
+/// Serves the first chunk and streams the others through `http_request_streaming_callback`.
+fn asset_response(req: &HttpRequest, asset_id: u64, cache_control: &str) -> HttpResponse {
+    // uploads that are not committed yet are not served
+    let (info, sha256) = match asset_info(asset_id) {
+        Some(info) => match info.sha256.clone() {
+            Some(sha256) => (info, sha256),
+            None => return error_response(404, "Asset not found"),
+        },
+        None => return error_response(404, "Asset not found"),
+    };
+
+    let etag = format!("\"{}\"", vec_to_hex_string(sha256));
+
+    let not_modified = req
+        .headers
+        .iter()
+        .any(|(name, value)| name.eq_ignore_ascii_case("If-None-Match") && *value == etag);
+
+    let builder = if not_modified {
+        HttpResponseBuilder::ok().body(vec![])
+    } else {
+        let first = asset_chunk(asset_id, 0).unwrap_or_default();
+
+        HttpResponseBuilder::ok()
+            .header("Content-Type", content_type(&info, &first))
+            .header("Content-Length", info.length)
+            .body(first.into_vec())
+    };
+
+    let mut response: HttpResponse = builder
+        .header("ETag", etag)
+        .header("Cache-Control", cache_control)
+        .build()
+        .into();
+
+    if not_modified {
+        response.status_code = 304;
+    } else {
+        response.streaming_strategy = streaming_strategy(&info, 1);
+    }
+
+    response
+}
+
+fn streaming_strategy(info: &AssetInfo, index: u32) -> Option<StreamingStrategy> {
+    if index >= info.chunk_count {
+        return None;
+    }
+
+    Some(StreamingStrategy::Callback {
+        callback: StreamingCallback::new(ic_cdk::id(), "http_request_streaming_callback".into()),
+        token: StreamingCallbackToken {
+            asset_id: info.asset_id,
+            index,
+        },
+    })
+}
+
+pub fn streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    let info = asset_info(token.asset_id).filter(|info| info.sha256.is_some());
+
+    let (info, body) = match info.zip(asset_chunk(token.asset_id, token.index)) {
+        Some(found) => found,
+        None => ic_cdk::trap("Invalid Streaming Token"),
+    };
+
+    StreamingCallbackHttpResponse {
+        body,
+        token: streaming_strategy(&info, token.index + 1).map(|strategy| match strategy {
+            StreamingStrategy::Callback { token, .. } => token,
+        }),
+    }
+}
+
+/// The stored content type, or one sniffed from the first bytes for inline images.
+fn content_type(info: &AssetInfo, first_chunk: &[u8]) -> String {
+    if info.content_type != DEFAULT_CONTENT_TYPE {
+        return info.content_type.clone();
+    }
+
+    detect_content_type(first_chunk)
+        .unwrap_or(DEFAULT_CONTENT_TYPE)
+        .into()
+}
+
+pub fn detect_content_type(bytes: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: [(&[u8], &str); 5] = [
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+    ];
+
+    if bytes.len() >= 12 && &bytes[..4] == b"RIFF" && &bytes[8..12] == b"WEBP" {
+        return Some("image/webp");
+    }
+
+    if let Some((_, content_type)) = SIGNATURES
+        .iter()
+        .find(|(signature, _)| bytes.starts_with(signature))
+    {
+        return Some(content_type);
+    }
+
+    let head = String::from_utf8_lossy(&bytes[..bytes.len().min(256)]);
+    if head.contains("<svg") {
+        return Some("image/svg+xml");
+    }
+
+    None
+}
+
+#[test]
+fn test_detect_content_type() {
+    assert_eq!(
+        detect_content_type(b"\x89PNG\r\n\x1a\n\0\0"),
+        Some("image/png")
+    );
+    assert_eq!(detect_content_type(b"\xff\xd8\xff\xe0"), Some("image/jpeg"));
+    assert_eq!(
+        detect_content_type(b"RIFF\0\0\0\0WEBPVP8 "),
+        Some("image/webp")
+    );
+    assert_eq!(
+        detect_content_type(b"<?xml version=\"1.0\"?><svg xmlns=\"\"/>"),
+        Some("image/svg+xml")
+    );
+    assert_eq!(detect_content_type(b"plain"), None);
+}
+
+#[test]
+fn test_parse_evm_token_id() {
+    assert_eq!(parse_evm_token_id("42"), Some(42));
+    assert_eq!(
+        parse_evm_token_id("000000000000000000000000000000000000000000000000000000000000002a"),
+        Some(42)
+    );
+    assert_eq!(parse_evm_token_id(&"0".repeat(64)), Some(0));
+    assert_eq!(parse_evm_token_id(&"f".repeat(64)), None);
+    assert_eq!(parse_evm_token_id("abc"), None);
+}
+
+#[test]
+fn test_http_errors_and_methods() {
+    let request = |method: &str, url: &str| HttpRequest {
+        method: method.into(),
+        url: url.into(),
+        headers: vec![],
+        body: Default::default(),
+    };
+    let status = |method: &str, url: &str| handle(&request(method, url)).status_code;
+
+    assert_eq!(status("GET", "/token"), 400);
+    assert_eq!(status("GET", "/token?id=abc"), 400);
+    assert_eq!(status("GET", "/token/abc"), 400);
+    assert_eq!(status("GET", "/token/999999"), 404);
+    assert_eq!(status("GET", "/block?id=999999"), 404);
+    assert_eq!(status("GET", "/evm/cknft/x/1"), 400);
+    assert_eq!(status("GET", "/missing"), 404);
+    assert_eq!(status("POST", "/token/1"), 405);
+
+    let response = handle(&request("OPTIONS", "/token/1"));
+    assert_eq!(response.status_code, 204);
+    assert!(response
+        .headers
+        .contains(&("Access-Control-Allow-Origin".into(), "*".into())));
+
+    let response = handle(&request("HEAD", "/missing"));
+    assert_eq!(response.status_code, 404);
+    assert!(response.body.is_empty());
+    assert_eq!(
+        serde_json::from_slice::<serde_json::Value>(&handle(&request("GET", "/missing")).body)
+            .unwrap()["code"],
+        404
+    );
+}
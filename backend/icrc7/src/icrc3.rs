@@ -0,0 +1,683 @@
+use crate::certification::{cbor, http_root_hash, update_certified_data};
+use crate::{
+    state::{first_local_block, get_archives, get_icrc7_config, get_log_tip, insert_dedup_entry},
+    state::{pending_archive, set_pending_archive},
+    state::{tx_deduplication_check, DedupKey, DEDUP_INDEX},
+    state::{
+        Archive, LogTip, TransferLog, ARCHIVES, ARCHIVE_WASM, BLOCK_LOG, LOG_TIP, TRANSFER_LOG,
+    },
+    types::{
+        ArchiveInitArgs, ArchiveOptions, ArchivedBlocks, BlockWithId, GetBlocksArgs,
+        GetBlocksCallback, GetBlocksResult, ICRC3ArchiveInfo, ICRC3DataCertificate, Memo,
+        SupportedBlockType, Value,
+    },
+};
+use b3_utils::ledger::{raw_sha256, ICRCAccount};
+use candid::{Encode, Nat, Principal};
+use ic_cdk::api::management_canister::main::{
+    create_canister, install_code, CanisterInstallMode, CanisterSettings, CreateCanisterArgument,
+    InstallCodeArgument,
+};
+use ic_certification::{empty, fork, labeled, leaf, pruned, HashTree};
+use serde_bytes::ByteBuf;
+use std::cell::Cell;
+
+pub const BTYPE_MINT: &str = "7mint";
+pub const BTYPE_BURN: &str = "7burn";
+pub const BTYPE_TRANSFER: &str = "7xfer";
+pub const BTYPE_APPROVE: &str = "37approve";
+pub const BTYPE_APPROVE_COLLECTION: &str = "37approve_coll";
+pub const BTYPE_REVOKE: &str = "37revoke";
+pub const BTYPE_REVOKE_COLLECTION: &str = "37revoke_coll";
+pub const BTYPE_TRANSFER_FROM: &str = "37xfer";
+/// Snapshot of the minting authorities after every change.
+pub const BTYPE_MINTERS: &str = "cknft_minters";
+
+pub const MAX_BLOCKS_PER_RESPONSE: u64 = 1000;
+
+/// Cycles sent along `create_canister` when `cycles_for_archive_creation` is unset, enough for
+/// the creation fee and the storage of a full archive.
+pub const DEFAULT_ARCHIVE_CREATION_CYCLES: u128 = 10_000_000_000_000;
+
+thread_local! {
+    // one archiving round at a time, blocks are only removed once their archive confirmed them
+    static ARCHIVING: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Set while an archiving round runs, dropped with the round even when it traps after an await.
+struct ArchivingGuard;
+
+impl ArchivingGuard {
+    fn acquire() -> Option<Self> {
+        (!ARCHIVING.with(|archiving| archiving.replace(true))).then_some(Self)
+    }
+}
+
+impl Drop for ArchivingGuard {
+    fn drop(&mut self) {
+        ARCHIVING.with(|archiving| archiving.set(false));
+    }
+}
+
+pub fn supported_block_types() -> Vec<SupportedBlockType> {
+    let icrc7 = "https://github.com/dfinity/ICRC/ICRCs/ICRC-7";
+    let icrc37 = "https://github.com/dfinity/ICRC/ICRCs/ICRC-37";
+    let cknft = "https://github.com/b3hr4d/cknft";
+
+    [
+        (BTYPE_MINT, icrc7),
+        (BTYPE_BURN, icrc7),
+        (BTYPE_TRANSFER, icrc7),
+        (BTYPE_APPROVE, icrc37),
+        (BTYPE_APPROVE_COLLECTION, icrc37),
+        (BTYPE_REVOKE, icrc37),
+        (BTYPE_REVOKE_COLLECTION, icrc37),
+        (BTYPE_TRANSFER_FROM, icrc37),
+        (BTYPE_MINTERS, cknft),
+    ]
+    .into_iter()
+    .map(|(block_type, url)| SupportedBlockType {
+        block_type: block_type.into(),
+        url: url.into(),
+    })
+    .collect()
+}
+
+/// Representation-independent hash of an ICRC-3 value.
+pub fn hash_value(value: &Value) -> [u8; 32] {
+    let bytes = match value {
+        Value::Blob(blob) => raw_sha256(blob),
+        Value::Text(text) => raw_sha256(text.as_bytes()),
+        Value::Nat(nat) => {
+            let mut buf = vec![];
+            nat.encode(&mut buf).unwrap();
+            raw_sha256(&buf)
+        }
+        Value::Int(int) => {
+            let mut buf = vec![];
+            int.encode(&mut buf).unwrap();
+            raw_sha256(&buf)
+        }
+        Value::Array(values) => {
+            let mut buf = Vec::with_capacity(values.len() * 32);
+            for value in values {
+                buf.extend_from_slice(&hash_value(value));
+            }
+            raw_sha256(&buf)
+        }
+        Value::Map(entries) => {
+            let mut hashes: Vec<Vec<u8>> = entries
+                .iter()
+                .map(|(key, value)| {
+                    let mut pair = raw_sha256(key.as_bytes());
+                    pair.extend_from_slice(&hash_value(value));
+                    pair
+                })
+                .collect();
+            hashes.sort();
+
+            raw_sha256(&hashes.concat())
+        }
+    };
+
+    bytes.try_into().unwrap()
+}
+
+fn dedup_hash(btype: &str, tx: Value) -> [u8; 32] {
+    hash_value(&Value::Map(vec![
+        ("btype".into(), Value::Text(btype.into())),
+        ("tx".into(), tx),
+    ]))
+}
+
+/// Indexes the blocks with a `created_at_time` from `since` on, for logs written before the
+/// dedup index existed.
+pub fn rebuild_dedup_index(since: u64) {
+    if DEDUP_INDEX.with(|index| !index.borrow().is_empty()) {
+        return;
+    }
+
+    let since = Nat::from(since);
+
+    let entries: Vec<(DedupKey, u64)> = BLOCK_LOG.with(|log| {
+        log.borrow()
+            .iter()
+            .rev()
+            .take_while(|(_, block)| block.get("ts").and_then(Value::as_nat) >= Some(&since))
+            .filter_map(|(index, block)| {
+                let btype = block.get("btype").and_then(Value::as_text)?;
+                let tx = block.get("tx")?;
+                let created_at_time = tx.get("ts").and_then(Value::as_nat)?;
+
+                Some((
+                    DedupKey {
+                        created_at_time: u64::try_from(&created_at_time.0).ok()?,
+                        hash: dedup_hash(btype, tx.clone()),
+                    },
+                    index,
+                ))
+            })
+            .collect()
+    });
+
+    for (key, index) in entries {
+        insert_dedup_entry(key, index);
+    }
+}
+
+/// ICRC-3 account encoding, the subaccount is only present when it is not the default one.
+pub fn account_value(account: &ICRCAccount) -> Value {
+    let mut parts = vec![Value::Blob(ByteBuf::from(account.owner().as_slice()))];
+
+    if !account.effective_subaccount().is_default() {
+        parts.push(Value::Blob(ByteBuf::from(
+            account.effective_subaccount().to_vec(),
+        )));
+    }
+
+    Value::Array(parts)
+}
+
+pub struct BlockBuilder {
+    btype: &'static str,
+    created_at_time: Option<u64>,
+    tx: Vec<(String, Value)>,
+    request_key: Option<DedupKey>,
+}
+
+impl BlockBuilder {
+    pub fn new(btype: &'static str) -> Self {
+        Self {
+            btype,
+            created_at_time: None,
+            tx: vec![],
+            request_key: None,
+        }
+    }
+
+    pub fn tid(mut self, id: u128) -> Self {
+        self.tx.push(("tid".into(), Value::Nat(Nat::from(id))));
+        self
+    }
+
+    pub fn account(mut self, name: &str, account: &ICRCAccount) -> Self {
+        self.tx.push((name.into(), account_value(account)));
+        self
+    }
+
+    pub fn opt_account(self, name: &str, account: Option<&ICRCAccount>) -> Self {
+        match account {
+            Some(account) => self.account(name, account),
+            None => self,
+        }
+    }
+
+    pub fn principal(mut self, name: &str, principal: &Principal) -> Self {
+        self.tx.push((
+            name.into(),
+            Value::Blob(ByteBuf::from(principal.as_slice())),
+        ));
+        self
+    }
+
+    pub fn principals(mut self, name: &str, principals: &[Principal]) -> Self {
+        let principals = principals
+            .iter()
+            .map(|principal| Value::Blob(ByteBuf::from(principal.as_slice())))
+            .collect();
+
+        self.tx.push((name.into(), Value::Array(principals)));
+        self
+    }
+
+    pub fn memo(mut self, memo: &Option<Memo>) -> Self {
+        if let Some(memo) = memo {
+            self.tx.push(("memo".into(), Value::Blob(memo.0.clone())));
+        }
+        self
+    }
+
+    /// The caller supplied `created_at_time`, omitted when absent.
+    pub fn ts(mut self, created_at_time: Option<u64>) -> Self {
+        if let Some(ts) = created_at_time {
+            self.tx.push(("ts".into(), Value::Nat(Nat::from(ts))));
+        }
+        self.created_at_time = created_at_time;
+        self
+    }
+
+    pub fn exp(mut self, expires_at: Option<u64>) -> Self {
+        if let Some(exp) = expires_at {
+            self.tx.push(("exp".into(), Value::Nat(Nat::from(exp))));
+        }
+        self
+    }
+
+    /// Only blocks with a caller supplied `created_at_time` are deduplicated.
+    pub fn dedup_key(&self) -> Option<DedupKey> {
+        if let Some(key) = &self.request_key {
+            return Some(key.clone());
+        }
+
+        let created_at_time = self.created_at_time?;
+
+        Some(DedupKey {
+            created_at_time,
+            hash: dedup_hash(self.btype, self.tx()),
+        })
+    }
+
+    /// Index of the logged block of the same request, checked before anything is loaded so
+    /// that a retry is reported as a duplicate rather than failing on the changed state.
+    pub fn duplicate_of(&self) -> Option<u128> {
+        self.dedup_key()
+            .as_ref()
+            .and_then(tx_deduplication_check)
+            .map(u128::from)
+    }
+
+    /// Deduplicates the block under the key of `request`, for blocks that also record state
+    /// a retry cannot supply, such as the owner of a token moved by an approved spender.
+    pub fn dedup_as(mut self, request: &BlockBuilder) -> Self {
+        self.request_key = request.dedup_key();
+        self
+    }
+
+    pub fn tx(&self) -> Value {
+        Value::Map(self.tx.clone())
+    }
+
+    /// Appends the block to the log and archives old blocks once the threshold is reached.
+    pub fn log(self, current_time: u64) -> u128 {
+        let dedup_key = self.dedup_key();
+        let index = self.append(current_time);
+
+        if let Some(key) = dedup_key {
+            insert_dedup_entry(key, index as u64);
+        }
+        archive_if_needed();
+
+        index
+    }
+
+    /// Appends the block to the log, chaining it to the previous one, and returns its index.
+    fn append(self, current_time: u64) -> u128 {
+        let tip = get_log_tip();
+
+        let mut block = vec![
+            ("btype".to_string(), Value::Text(self.btype.into())),
+            ("ts".to_string(), Value::Nat(Nat::from(current_time))),
+        ];
+        if let Some(phash) = tip.last_hash {
+            block.push(("phash".into(), Value::Blob(ByteBuf::from(phash))));
+        }
+        block.push(("tx".into(), Value::Map(self.tx)));
+
+        let block = Value::Map(block);
+        let hash = hash_value(&block);
+        let index = tip.length;
+
+        BLOCK_LOG.with(|log| log.borrow_mut().insert(index, block));
+        let tip = LogTip {
+            length: index + 1,
+            last_hash: Some(hash.to_vec()),
+        };
+        LOG_TIP.with(|cell| cell.borrow_mut().set(tip).unwrap());
+        update_certified_data();
+
+        index as u128
+    }
+}
+
+/// ICRC-3 tip tree, `last_block_hash` and the LEB128 encoded `last_block_index`.
+pub fn tip_tree(tip: &LogTip) -> HashTree {
+    match &tip.last_hash {
+        None => empty(),
+        Some(hash) => {
+            let mut index = vec![];
+            Nat::from(tip.length - 1).encode(&mut index).unwrap();
+
+            fork(
+                labeled("last_block_hash", leaf(hash.clone())),
+                labeled("last_block_index", leaf(index)),
+            )
+        }
+    }
+}
+
+/// The HTTP certification tree shares the certified data and is pruned from the witness.
+pub fn tip_certificate() -> Option<ICRC3DataCertificate> {
+    let certificate = ic_cdk::api::data_certificate()?;
+    let hash_tree = fork(pruned(http_root_hash()), tip_tree(&get_log_tip()));
+
+    Some(ICRC3DataCertificate {
+        certificate: ByteBuf::from(certificate),
+        hash_tree: ByteBuf::from(cbor(&hash_tree)),
+    })
+}
+
+pub fn get_block(index: u64) -> Option<Value> {
+    BLOCK_LOG.with(|log| log.borrow().get(&index))
+}
+
+pub fn get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    let log_length = get_log_tip().length;
+    let first_local = first_local_block();
+    let archives = get_archives();
+    let mut archived_args: Vec<Vec<GetBlocksArgs>> = vec![vec![]; archives.len()];
+    let mut budget = MAX_BLOCKS_PER_RESPONSE;
+    let mut blocks = vec![];
+
+    for arg in args.iter() {
+        let end = arg.start.saturating_add(arg.length);
+
+        for (archive, archived) in archives.iter().zip(archived_args.iter_mut()) {
+            let start = arg.start.max(archive.start as u128);
+            let stop = end.min((archive.start + archive.length) as u128);
+
+            if start < stop {
+                archived.push(GetBlocksArgs {
+                    start,
+                    length: stop - start,
+                });
+            }
+        }
+    }
+
+    BLOCK_LOG.with(|log| {
+        let log = log.borrow();
+
+        for arg in args {
+            let start = arg.start.clamp(first_local as u128, log_length as u128) as u64;
+            let end = arg.start.saturating_add(arg.length).min(log_length as u128) as u64;
+            let end = end.max(start).min(start + budget);
+
+            for (id, block) in log.range(start..end) {
+                blocks.push(BlockWithId {
+                    id: id as u128,
+                    block,
+                });
+            }
+
+            budget -= end - start;
+        }
+    });
+
+    GetBlocksResult {
+        log_length: log_length as u128,
+        blocks,
+        archived_blocks: archives
+            .into_iter()
+            .zip(archived_args)
+            .filter(|(_, args)| !args.is_empty())
+            .map(|(archive, args)| ArchivedBlocks {
+                args,
+                callback: GetBlocksCallback::new(archive.canister_id, "icrc3_get_blocks".into()),
+            })
+            .collect(),
+    }
+}
+
+/// Archives with at least one block, those after `from` when it is given.
+pub fn get_archive_infos(from: Option<Principal>) -> Vec<ICRC3ArchiveInfo> {
+    get_archives()
+        .into_iter()
+        .filter(|archive| archive.length > 0)
+        .skip_while(|archive| from.is_some_and(|from| from != archive.canister_id))
+        .skip(from.is_some() as usize)
+        .map(|archive| ICRC3ArchiveInfo {
+            canister_id: archive.canister_id,
+            start: archive.start as u128,
+            end: (archive.start + archive.length - 1) as u128,
+        })
+        .collect()
+}
+
+/// Spawns an archiving round when the local log outgrew `trigger_threshold`.
+fn archive_if_needed() {
+    let options = match get_icrc7_config().archive_options {
+        Some(options) => options,
+        None => return,
+    };
+
+    let local_blocks = BLOCK_LOG.with(|log| log.borrow().len());
+
+    if local_blocks < options.trigger_threshold {
+        return;
+    }
+
+    let guard = match ArchivingGuard::acquire() {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    ic_cdk::spawn(async move {
+        let _guard = guard;
+
+        if let Err(err) = archive_blocks(options).await {
+            ic_cdk::println!("Archiving failed: {}", err);
+        }
+    });
+}
+
+/// Moves the oldest `num_blocks_to_archive` local blocks to the last archive, spawning a new
+/// one when it is full.
+async fn archive_blocks(options: ArchiveOptions) -> Result<(), String> {
+    let first_local = first_local_block();
+
+    let archive = match get_archives().pop() {
+        Some(archive) if archive.length < options.max_blocks_per_archive => archive,
+        _ => create_archive(&options, first_local).await?,
+    };
+
+    let count = options
+        .num_blocks_to_archive
+        .min(options.max_blocks_per_archive - archive.length);
+
+    let blocks: Vec<Value> = BLOCK_LOG.with(|log| {
+        log.borrow()
+            .range(first_local..first_local + count)
+            .map(|(_, block)| block)
+            .collect()
+    });
+    let count = blocks.len() as u64;
+
+    ic_cdk::call::<_, ()>(archive.canister_id, "append_blocks", (blocks,))
+        .await
+        .map_err(|(_, msg)| msg)?;
+
+    BLOCK_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+
+        for index in first_local..first_local + count {
+            log.remove(&index);
+        }
+    });
+
+    ARCHIVES.with(|archives| {
+        archives.borrow_mut().insert(
+            archive.start,
+            Archive {
+                length: archive.length + count,
+                ..archive
+            },
+        )
+    });
+
+    Ok(())
+}
+
+async fn create_archive(options: &ArchiveOptions, start: u64) -> Result<Archive, String> {
+    let wasm_module = ARCHIVE_WASM.with(|wasm| wasm.borrow().get().clone());
+
+    if wasm_module.is_empty() {
+        return Err("Archive Wasm Not Set".into());
+    }
+
+    // a canister whose install failed is reused instead of spawning another one
+    let (canister_id, mode) = match pending_archive() {
+        Some(canister_id) => (canister_id, CanisterInstallMode::Reinstall),
+        None => {
+            let mut controllers = options.controllers.clone().unwrap_or_default();
+            controllers.push(ic_cdk::id());
+
+            let (record,) = create_canister(
+                CreateCanisterArgument {
+                    settings: Some(CanisterSettings {
+                        controllers: Some(controllers),
+                        ..Default::default()
+                    }),
+                },
+                options
+                    .cycles_for_archive_creation
+                    .unwrap_or(DEFAULT_ARCHIVE_CREATION_CYCLES),
+            )
+            .await
+            .map_err(|(_, msg)| msg)?;
+
+            set_pending_archive(Some(record.canister_id));
+
+            (record.canister_id, CanisterInstallMode::Install)
+        }
+    };
+
+    let arg = Encode!(&ArchiveInitArgs {
+        ledger_id: ic_cdk::id(),
+        start,
+        max_blocks: options.max_blocks_per_archive,
+    })
+    .unwrap();
+
+    install_code(InstallCodeArgument {
+        mode,
+        canister_id,
+        wasm_module,
+        arg,
+    })
+    .await
+    .map_err(|(_, msg)| msg)?;
+
+    set_pending_archive(None);
+
+    let archive = Archive {
+        canister_id,
+        start,
+        length: 0,
+    };
+
+    ARCHIVES.with(|archives| archives.borrow_mut().insert(start, archive.clone()));
+
+    Ok(archive)
+}
+
+/// Re-logs the entries of the pre ICRC-3 `TRANSFER_LOG` as `7xfer` blocks, once.
+pub fn migrate_transfer_log() {
+    if get_log_tip().length != 0 {
+        return;
+    }
+
+    let legacy: Vec<TransferLog> = TRANSFER_LOG.with(|log| log.borrow().iter().collect());
+
+    for log in legacy {
+        BlockBuilder::new(BTYPE_TRANSFER)
+            .tid(log.id)
+            .account("from", &log.from)
+            .account("to", &log.to)
+            .memo(&log.memo)
+            .append(log.at);
+    }
+}
+
+#[test]
+fn test_hash_value() {
+    // test vectors from the ICRC-3 specification
+    assert_eq!(
+        b3_utils::vec_to_hex_string(hash_value(&Value::Nat(Nat::from(42u64)))),
+        "684888c0ebb17f374298b65ee2807526c066094c701bcc7ebbe1c1095f494fc1"
+    );
+    assert_eq!(
+        b3_utils::vec_to_hex_string(hash_value(&Value::Int(candid::Int::from(-42)))),
+        "de5a6f78116eca62d7fc5ce159d23ae6b889b365a1739ad2cf36f925a140d0cc"
+    );
+    assert_eq!(
+        b3_utils::vec_to_hex_string(hash_value(&Value::Text("Hello, World!".into()))),
+        "dffd6021bb2bd5b0af676290809ec3a53191dd81c7f70a4b28688a362182986f"
+    );
+    assert_eq!(
+        b3_utils::vec_to_hex_string(hash_value(&Value::Blob(ByteBuf::from(
+            b"\x01\x02\x03\x04".to_vec()
+        )))),
+        "9f64a747e1b97f131fabb6b447296c9b6f0201e79fb3c5356e6c77e89b6a806a"
+    );
+    assert_eq!(
+        b3_utils::vec_to_hex_string(hash_value(&Value::Array(vec![
+            Value::Nat(Nat::from(3u64)),
+            Value::Text("foo".into()),
+            Value::Blob(ByteBuf::from(b"\x05\x06".to_vec())),
+        ]))),
+        "514a04011caa503990d446b7dec5d79e19c221ae607fb08b2848c67734d468d6"
+    );
+    assert_eq!(
+        b3_utils::vec_to_hex_string(hash_value(&Value::Map(vec![
+            ("from".into(), Value::Blob(ByteBuf::from(
+                b"\x00\xab\xcd\xef\x00\x12\x34\x00\x56\x78\x9a\x00\xbc\xde\xf0\x00\x01\x23\x45\x67\x89\x00\xab\xcd\xef\x01".to_vec()
+            ))),
+            ("to".into(), Value::Blob(ByteBuf::from(
+                b"\x00\xab\x0d\xef\x00\x12\x34\x00\x56\x78\x9a\x00\xbc\xde\xf0\x00\x01\x23\x45\x67\x89\x00\xab\xcd\xef\x01".to_vec()
+            ))),
+            ("amount".into(), Value::Nat(Nat::from(42u64))),
+            ("created_at".into(), Value::Nat(Nat::from(1699218263u64))),
+            ("memo".into(), Value::Nat(Nat::from(0u64))),
+        ]))),
+        "c56ece650e1de4269c5bdeff7875949e3e2033f85b2d193c2ff4f7f78bdcfc75"
+    );
+}
+
+#[test]
+fn test_get_blocks_redirects_archived_ranges() {
+    let archive_id = Principal::from_slice(&[7; 29]);
+
+    // blocks 0..3 were moved to the archive, 3 and 4 are still local
+    BLOCK_LOG.with(|log| {
+        let mut log = log.borrow_mut();
+        for index in 3..5 {
+            log.insert(index, BlockBuilder::new(BTYPE_MINT).tid(index as u128).tx());
+        }
+    });
+    LOG_TIP.with(|tip| {
+        tip.borrow_mut()
+            .set(LogTip {
+                length: 5,
+                last_hash: None,
+            })
+            .unwrap()
+    });
+    ARCHIVES.with(|archives| {
+        archives.borrow_mut().insert(
+            0,
+            Archive {
+                canister_id: archive_id,
+                start: 0,
+                length: 3,
+            },
+        )
+    });
+
+    let result = get_blocks(vec![GetBlocksArgs {
+        start: 1,
+        length: 10,
+    }]);
+
+    assert_eq!(result.log_length, 5);
+    assert_eq!(
+        result.blocks.iter().map(|b| b.id).collect::<Vec<_>>(),
+        vec![3, 4]
+    );
+    assert_eq!(result.archived_blocks.len(), 1);
+    assert_eq!(result.archived_blocks[0].args[0].start, 1);
+    assert_eq!(result.archived_blocks[0].args[0].length, 2);
+
+    let infos = get_archive_infos(None);
+    assert_eq!((infos[0].start, infos[0].end), (0, 2));
+    assert!(get_archive_infos(Some(archive_id)).is_empty());
+}
@@ -1,42 +1,182 @@
+pub mod assets;
+pub mod bridge;
+pub mod certification;
 pub mod crypto;
+pub mod evm_rpc;
+pub mod guards;
+pub mod http;
+pub mod icrc3;
 pub mod state;
 pub mod types;
 
+use crate::assets::{
+    create_asset, is_committed_by, migrate_token_images, prune_stale_uploads, DEFAULT_CONTENT_TYPE,
+};
+use crate::bridge::{BurnEvent, EXPIRY_GRACE_PERIOD};
+use crate::certification::{certify_all, update_certified_data};
 use crate::crypto::EcdsaSignature;
+use crate::evm_rpc::{outcall_cycles, EvmRpc, HttpsEvmRpc};
+use crate::guards::{
+    caller_is_bridge_operator, caller_is_config_admin, caller_is_minter, caller_is_owner,
+    caller_is_pauser, not_paused,
+};
+use crate::icrc3::{
+    get_archive_infos, get_blocks, migrate_transfer_log, rebuild_dedup_index,
+    supported_block_types, tip_certificate, BlockBuilder, BTYPE_APPROVE, BTYPE_APPROVE_COLLECTION,
+    BTYPE_BURN, BTYPE_MINT, BTYPE_MINTERS, BTYPE_REVOKE, BTYPE_REVOKE_COLLECTION, BTYPE_TRANSFER,
+    BTYPE_TRANSFER_FROM,
+};
 use crate::state::{calc_msgid, PUBLIC_KEY, SIGNATURE_MAP, STATUS_MAP};
+use crate::state::{insert_processed_burn, processed_burn, BurnEventKey};
 use crate::types::{CollectionMetadata, MintState, MintStatus, Standard};
 use crate::{
     state::Token,
-    state::{CollectionConfig, CONFIG},
+    state::{Approval, CollectionConfig, CONFIG},
     types::TransferError,
-    types::{MintArgs, TransferArg},
+    types::{ApprovalArgs, ApprovalError, MintArgs, TransferArg, TransferResult},
+    types::{
+        ApproveCollectionArg, ApproveCollectionError, ApproveTokenArg, ApproveTokenError,
+        CollectionApproval, IsApprovedArg, RevokeCollectionApprovalArg,
+        RevokeCollectionApprovalError, RevokeTokenApprovalArg, RevokeTokenApprovalError,
+        TokenApproval, TransferFromArg, TransferFromError,
+    },
+    types::{AssetError, AssetInfo, BeginAssetUploadArg, CommitAssetUploadArg, PutAssetChunkArg},
+    types::{BurnArg, BurnError, BurnResult},
+    types::{BurnWithdrawal, WithdrawArg, WithdrawError},
+    types::{
+        GetArchivesArgs, GetBlocksArgs, GetBlocksResult, ICRC3ArchiveInfo, ICRC3DataCertificate,
+        SupportedBlockType,
+    },
+    types::{HttpResponse, StreamingCallbackHttpResponse, StreamingCallbackToken},
+    types::{MintError, MintResult},
+    types::{Role, RoleAssignment},
 };
-use b3_utils::http::{HttpRequest, HttpResponse, HttpResponseBuilder};
+use b3_utils::http::HttpRequest;
 use b3_utils::ledger::{raw_keccak256, ICRC1MetadataValue, ICRCAccount};
-use b3_utils::memory::with_stable_mem;
 use b3_utils::nonce::Nonce;
-use b3_utils::{caller_is_controller, vec_to_hex_string_with_0x};
+use b3_utils::vec_to_hex_string_with_0x;
 use b3_utils::{hex_string_with_0x_to_vec, Subaccount};
+use candid::Principal;
 use ic_cdk::api::management_canister::ecdsa::{
     ecdsa_public_key, sign_with_ecdsa, EcdsaCurve, EcdsaKeyId, EcdsaPublicKeyArgument,
     EcdsaPublicKeyResponse, SignWithEcdsaArgument, SignWithEcdsaResponse,
 };
-use ic_cdk::{init, query, update};
+use ic_cdk::api::management_canister::http_request::{
+    HttpResponse as OutcallHttpResponse, TransformArgs,
+};
+use ic_cdk::{init, post_upgrade, query, update};
 use k256::ecdsa::{RecoveryId, Signature, VerifyingKey};
+use serde_bytes::ByteBuf;
 use state::{
-    get_icrc7_config, get_total_supply, id_validity_check, increment_total_supply, increment_tx_id,
-    tx_deduplication_check, TransferLog, NONCE_MAP, TOKENS, TOTAL_SUPPLY, TRANSFER_LOG,
+    approve_collection, collection_approvals, created_at_time_check, decrement_total_supply,
+    get_icrc7_config, get_total_supply, increment_total_supply, insert_token, is_burned_id,
+    memo_size_check, next_token_id, owner_token_count, owner_token_ids, permitted_time_window,
+    prune_dedup_index, query_batch_size_check, rebuild_owner_index, remove_token,
+    revoke_collection_approvals, role_assignments, role_holders, take_value, token_ids,
+    update_batch_size_check, ARCHIVE_WASM, BURNED_IDS, LATEST_STATE_VERSION, NONCE_MAP, TOKENS,
+    TOTAL_SUPPLY,
 };
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
 use types::SelfMintArgs;
 
+const DEDUP_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+const UPLOAD_PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+const MINT_STATUS_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+thread_local! {
+    static CHECKING_MINTS: Cell<bool> = const { Cell::new(false) };
+    /// Tokens with a bridge call awaiting the management canister or the EVM providers.
+    static BRIDGE_LOCKS: RefCell<BTreeSet<u128>> = RefCell::default();
+    /// Last call of each caller within `RPC_CALL_COOLDOWN` that attached no cycles.
+    static UNPAID_RPC_CALLS: RefCell<HashMap<Principal, u64>> = RefCell::default();
+}
+
+/// Held across the awaits of a bridge call on a token, released when dropped, which the
+/// cleanup of a trapped call does as well.
+struct BridgeLock(u128);
+
+impl BridgeLock {
+    fn acquire(id: u128) -> Result<Self, String> {
+        if !BRIDGE_LOCKS.with(|locks| locks.borrow_mut().insert(id)) {
+            return Err(format!("Token {} has a bridge call in progress", id));
+        }
+
+        Ok(Self(id))
+    }
+
+    fn is_locked(id: u128) -> bool {
+        BRIDGE_LOCKS.with(|locks| locks.borrow().contains(&id))
+    }
+}
+
+impl Drop for BridgeLock {
+    fn drop(&mut self) {
+        BRIDGE_LOCKS.with(|locks| locks.borrow_mut().remove(&self.0));
+    }
+}
+
+/// Set while the timer checks the pending mints, released with the run even when it traps.
+struct CheckingMintsGuard;
+
+impl CheckingMintsGuard {
+    fn acquire() -> Option<Self> {
+        (!CHECKING_MINTS.with(|checking| checking.replace(true))).then_some(Self)
+    }
+}
+
+impl Drop for CheckingMintsGuard {
+    fn drop(&mut self) {
+        CHECKING_MINTS.with(|checking| checking.set(false));
+    }
+}
+
 #[init]
 pub fn init(arg: CollectionConfig) {
+    if let Some(minter) = arg.minting_authority {
+        state::grant_role(Role::Minter, minter);
+        log_minters(ic_cdk::api::time());
+    }
+
     CONFIG.with(|c| {
         let mut c = c.borrow_mut();
 
         c.set(arg).unwrap();
     });
+    state::set_state_version(LATEST_STATE_VERSION);
+
+    certify_all();
+    start_pruning();
+    start_mint_status_checks();
+}
+
+#[post_upgrade]
+pub fn post_upgrade() {
+    // collections minted before the owner index existed need it built once
+    rebuild_owner_index();
+    state::rebuild_pending_mints();
+    migrate_transfer_log();
+    migrate_token_images(ic_cdk::id());
+    certify_all();
+
+    let (permitted_past_time, _) = permitted_time_window(&get_icrc7_config(), ic_cdk::api::time());
+    rebuild_dedup_index(permitted_past_time);
+    start_pruning();
+    start_mint_status_checks();
+}
+
+/// Timers do not survive upgrades, so this runs from both `init` and `post_upgrade`.
+fn start_pruning() {
+    ic_cdk_timers::set_timer_interval(DEDUP_PRUNE_INTERVAL, || {
+        let (permitted_past_time, _) =
+            permitted_time_window(&get_icrc7_config(), ic_cdk::api::time());
+
+        prune_dedup_index(permitted_past_time);
+    });
+    ic_cdk_timers::set_timer_interval(UPLOAD_PRUNE_INTERVAL, || {
+        prune_stale_uploads(ic_cdk::api::time());
+    });
 }
 
 /// ======== Query ========
@@ -56,7 +196,7 @@ pub fn ethereum_address() -> String {
         .to_encoded_point(false);
     let ethereum_pubkey = &uncompressed_pubkey.as_bytes()[1..]; // trim off the first 0x04 byte
 
-    let hashed_payload = raw_keccak256(&ethereum_pubkey).to_vec();
+    let hashed_payload = raw_keccak256(ethereum_pubkey).to_vec();
 
     vec_to_hex_string_with_0x(&hashed_payload[12..32])
 }
@@ -88,7 +228,7 @@ pub fn icrc7_logo() -> Option<String> {
 
 #[query]
 pub fn icrc7_total_supply() -> u128 {
-    TOTAL_SUPPLY.with(|s| s.borrow().get().clone())
+    TOTAL_SUPPLY.with(|s| *s.borrow().get())
 }
 
 #[query]
@@ -102,363 +242,2259 @@ pub fn icrc7_collection_metadata() -> CollectionMetadata {
 }
 
 #[query]
-pub fn icrc7_metadata(id: u128) -> Vec<(String, ICRC1MetadataValue)> {
-    match TOKENS.with(|tokens| tokens.borrow().get(&id)) {
-        None => ic_cdk::trap("Invalid Token Id"),
-        Some(token) => token.token_metadata(),
+pub fn icrc7_token_metadata(
+    token_ids: Vec<u128>,
+) -> Vec<Option<Vec<(String, ICRC1MetadataValue)>>> {
+    let config = get_icrc7_config();
+
+    if let Err(e) = query_batch_size_check(&config, token_ids.len()) {
+        ic_cdk::trap(&e)
     }
+
+    TOKENS.with(|tokens| {
+        let tokens = tokens.borrow();
+
+        token_ids
+            .iter()
+            .map(|id| tokens.get(id).map(|token| token.token_metadata()))
+            .collect()
+    })
 }
 
 #[query]
-pub fn icrc7_owner_of(id: u128) -> ICRCAccount {
+pub fn icrc7_owner_of(token_ids: Vec<u128>) -> Vec<Option<ICRCAccount>> {
+    let config = get_icrc7_config();
+
+    if let Err(e) = query_batch_size_check(&config, token_ids.len()) {
+        ic_cdk::trap(&e)
+    }
+
     TOKENS.with(|tokens| {
         let tokens = tokens.borrow();
-        match tokens.get(&id) {
-            None => ic_cdk::trap("Invalid Token Id"),
-            Some(token) => token.owner.clone(),
-        }
+
+        token_ids
+            .iter()
+            .map(|id| tokens.get(id).map(|token| token.owner()))
+            .collect()
     })
 }
 
 #[query]
-pub fn icrc7_balance_of(account: ICRCAccount) -> u128 {
-    let mut balance = 0;
+pub fn icrc7_balance_of(accounts: Vec<ICRCAccount>) -> Vec<u128> {
+    let config = get_icrc7_config();
 
-    TOKENS.with(|tokens| {
-        for (_, token) in tokens.borrow().iter() {
-            if token.owner == account {
-                balance += 1;
-                continue;
-            }
-        }
-    });
+    if let Err(e) = query_batch_size_check(&config, accounts.len()) {
+        ic_cdk::trap(&e)
+    }
 
-    balance
+    accounts.iter().map(owner_token_count).collect()
 }
 
 #[query]
-pub fn icrc7_tokens_of(account: ICRCAccount) -> Vec<u128> {
-    let mut ids = vec![];
-    TOKENS.with(|tokens| {
-        for (id, token) in tokens.borrow().iter() {
-            if token.owner == account {
-                ids.push(id.clone())
-            }
-        }
-    });
+pub fn icrc7_tokens(prev: Option<u128>, take: Option<u128>) -> Vec<u128> {
+    let config = get_icrc7_config();
+
+    token_ids(prev, take_value(&config, take))
+}
+
+#[query]
+pub fn icrc7_tokens_of(account: ICRCAccount, prev: Option<u128>, take: Option<u128>) -> Vec<u128> {
+    let config = get_icrc7_config();
 
-    ids
+    owner_token_ids(&account, prev, take_value(&config, take))
 }
 
 #[query]
 pub fn icrc7_supported_standards() -> Vec<Standard> {
-    vec![Standard {
-        name: "ICRC-7".into(),
-        url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".into(),
-    }]
+    vec![
+        Standard {
+            name: "ICRC-7".into(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-7".into(),
+        },
+        Standard {
+            name: "ICRC-37".into(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-37".into(),
+        },
+        Standard {
+            name: "ICRC-3".into(),
+            url: "https://github.com/dfinity/ICRC/ICRCs/ICRC-3".into(),
+        },
+    ]
 }
 
-/// ======== Update ========
+#[query]
+pub fn icrc3_get_blocks(args: Vec<GetBlocksArgs>) -> GetBlocksResult {
+    get_blocks(args)
+}
 
-#[update]
-pub fn icrc7_transfer(arg: TransferArg) -> Result<u128, TransferError> {
-    // checking if the token for the respective id is available or not
-    id_validity_check(arg.token_id);
+#[query]
+pub fn icrc3_get_archives(arg: GetArchivesArgs) -> Vec<ICRC3ArchiveInfo> {
+    get_archive_infos(arg.from)
+}
 
-    let caller = ICRCAccount::new(ic_cdk::caller(), arg.from_subaccount);
+#[query]
+pub fn icrc3_get_tip_certificate() -> Option<ICRC3DataCertificate> {
+    tip_certificate()
+}
 
-    let current_time = ic_cdk::api::time();
-    let mut tx_deduplication: HashMap<u128, TransferError> = HashMap::new();
+#[query]
+pub fn icrc3_supported_block_types() -> Vec<SupportedBlockType> {
+    supported_block_types()
+}
 
+#[query]
+pub fn icrc37_is_approved(args: Vec<IsApprovedArg>) -> Vec<bool> {
     let config = get_icrc7_config();
 
-    if let Some(arg_time) = arg.created_at_time {
-        let permitted_past_time = current_time - config.tx_window - config.permitted_drift;
-        let permitted_future_time = current_time + config.permitted_drift;
+    if let Err(e) = query_batch_size_check(&config, args.len()) {
+        ic_cdk::trap(&e)
+    }
 
-        if arg_time < permitted_past_time {
-            return Err(TransferError::TooOld);
-        }
-        if arg_time > permitted_future_time {
-            return Err(TransferError::CreatedInFuture {
-                ledger_time: current_time,
-            });
-        }
+    let permitted_time = ic_cdk::api::time() + config.permitted_drift;
 
-        if let Some(index) = tx_deduplication_check(
-            permitted_past_time,
-            arg_time,
-            &arg.memo,
-            arg.token_id,
-            &caller,
-            &arg.to,
-        ) {
-            tx_deduplication.insert(
-                arg.token_id,
-                TransferError::Duplicate {
-                    duplicate_of: index as u128,
-                },
-            );
-        }
-    }
+    args.into_iter()
+        .map(
+            |arg| match TOKENS.with(|tokens| tokens.borrow().get(&arg.token_id)) {
+                // anyone may ask, the approval must come from the subaccount holding the token
+                Some(token) => {
+                    let from = ICRCAccount::new(token.owner.owner(), arg.from_subaccount);
+
+                    token.owner == from && token.approval_check(permitted_time, &arg.spender)
+                }
+                None => false,
+            },
+        )
+        .collect()
+}
 
-    let token = match TOKENS.with(|tokens| tokens.borrow().get(&arg.token_id)) {
-        None => ic_cdk::trap("Invalid Id"),
+#[query]
+pub fn icrc37_get_token_approvals(
+    token_id: u128,
+    prev: Option<TokenApproval>,
+    take: Option<u128>,
+) -> Vec<TokenApproval> {
+    let config = get_icrc7_config();
+    let take = take_value(&config, take);
+
+    let token = match TOKENS.with(|tokens| tokens.borrow().get(&token_id)) {
+        None => return vec![],
         Some(token) => token,
     };
 
-    let approval_check = token.approval_check(current_time + config.permitted_drift, &caller);
-    if token.owner != caller && !approval_check {
-        return Err(TransferError::Unauthorized);
-    }
-
-    if let Some(e) = tx_deduplication.get(&arg.token_id) {
-        return Err(e.clone());
-    }
-    let mut token = TOKENS.with(|tokens| tokens.borrow().get(&arg.token_id).unwrap());
+    let current_time = ic_cdk::api::time();
+    let from_subaccount = token.owner.subaccount();
+
+    // approvals are kept in insertion order, resume right after the `prev` spender
+    let skip = match prev {
+        None => 0,
+        Some(prev) => token
+            .approvals
+            .iter()
+            .position(|a| a.account == prev.approval_info.spender)
+            .map_or(token.approvals.len(), |index| index + 1),
+    };
 
     token
-        .transfer(
-            current_time + config.permitted_drift,
-            &caller,
-            arg.to.clone(),
-        )
-        .unwrap();
+        .approvals
+        .iter()
+        .skip(skip)
+        .filter(|approval| approval.is_active(current_time))
+        .take(take)
+        .map(|approval| TokenApproval {
+            token_id,
+            approval_info: approval.info(from_subaccount.clone()),
+        })
+        .collect()
+}
 
-    let log = TransferLog {
-        id: arg.token_id,
-        at: current_time,
-        memo: arg.memo.clone(),
-        from: caller.clone(),
-        to: arg.to.clone(),
-    };
+#[query]
+pub fn icrc37_get_collection_approvals(
+    owner: ICRCAccount,
+    prev: Option<CollectionApproval>,
+    take: Option<u128>,
+) -> Vec<CollectionApproval> {
+    let config = get_icrc7_config();
+    let take = take_value(&config, take);
 
-    TOKENS.with(|tokens| tokens.borrow_mut().insert(arg.token_id, token));
-    TRANSFER_LOG.with(|log_ref| log_ref.borrow_mut().push(&log).unwrap());
+    let current_time = ic_cdk::api::time();
+    let prev = prev.map(|approval| approval.spender);
 
-    Ok(increment_tx_id())
+    collection_approvals(&owner, prev.as_ref(), take)
+        .into_iter()
+        .filter(|approval| approval.is_active(current_time))
+        .map(|approval| approval.info(owner.subaccount()))
+        .collect()
 }
 
-#[update(guard = "caller_is_controller")]
-pub fn mint(arg: MintArgs) -> u128 {
-    let token = Token {
-        id: arg.id,
-        name: arg.name,
-        description: arg.description,
-        image: arg.image,
-        owner: arg.to,
-        approvals: Vec::new(),
-    };
+/// ======== Update ========
 
+#[update(guard = "not_paused")]
+pub fn icrc7_transfer(args: Vec<TransferArg>) -> Vec<Option<TransferResult>> {
     let config = get_icrc7_config();
 
-    if let Some(cap) = config.supply_cap {
-        if cap < get_total_supply() {
-            ic_cdk::trap("Supply Cap Reached")
-        }
+    transfer_batch(&config, ic_cdk::api::time(), ic_cdk::caller(), &args)
+}
+
+fn transfer_batch(
+    config: &CollectionConfig,
+    current_time: u64,
+    caller: Principal,
+    args: &[TransferArg],
+) -> Vec<Option<TransferResult>> {
+    if let Err(message) = update_batch_size_check(config, args.len()) {
+        return vec![Some(Err(TransferError::GenericBatchError {
+            error_code: 0,
+            message,
+        }))];
     }
 
-    if TOKENS.with(|tokens| tokens.borrow().contains_key(&token.id)) {
-        ic_cdk::trap("Id Exist")
+    if !config.atomic_batch_transfers.unwrap_or(false) {
+        return args
+            .iter()
+            .map(|arg| Some(transfer_token(config, current_time, caller, arg)))
+            .collect();
     }
 
-    increment_total_supply();
+    // atomic mode: validate the whole batch first and only commit if every item passes
+    let mut pending: HashMap<u128, Token> = HashMap::new();
+    let mut prepared = Vec::with_capacity(args.len());
+    let mut results: Vec<Option<TransferResult>> = vec![None; args.len()];
+
+    for (index, arg) in args.iter().enumerate() {
+        let caller = ICRCAccount::new(caller, arg.from_subaccount.clone());
 
-    TOKENS.with(|tokens| tokens.borrow_mut().insert(token.id, token));
+        match prepare_transfer(config, current_time, &caller, arg, &pending) {
+            Ok((token, block)) => {
+                pending.insert(token.id, token.clone());
+                prepared.push((index, token, block));
+            }
+            Err(e) => results[index] = Some(Err(e)),
+        }
+    }
+
+    if prepared.len() != args.len() {
+        return results
+            .into_iter()
+            .map(|result| {
+                result.or(Some(Err(TransferError::GenericBatchError {
+                    error_code: 1,
+                    message: "Atomic batch transfer aborted".into(),
+                })))
+            })
+            .collect();
+    }
+
+    for (index, token, block) in prepared {
+        results[index] = Some(Ok(commit_transfer(current_time, token, block)));
+    }
 
-    increment_tx_id()
+    results
 }
 
-#[query]
-fn http_request(req: HttpRequest) -> HttpResponse {
-    match req.path() {
-        "/token" => {
-            let token_id = req.raw_query_param("id").unwrap();
-
-            let token = TOKENS.with(|tokens| {
-                let tokens = tokens.borrow();
-                match tokens.get(&token_id.parse::<u128>().unwrap_or_default()) {
-                    None => ic_cdk::trap("Invalid Token Id"),
-                    Some(token) => token,
-                }
-            });
+/// Validates `arg` against the current state, overlaid by `pending` tokens of the same batch,
+/// and returns the token with its new owner alongside the block to log.
+fn prepare_transfer(
+    config: &CollectionConfig,
+    current_time: u64,
+    caller: &ICRCAccount,
+    arg: &TransferArg,
+    pending: &HashMap<u128, Token>,
+) -> Result<(Token, BlockBuilder), TransferError> {
+    created_at_time_check(config, current_time, arg.created_at_time)?;
+
+    // a retry names the caller, the token owner may have changed since
+    let request = BlockBuilder::new(BTYPE_TRANSFER)
+        .tid(arg.token_id)
+        .account("from", caller)
+        .account("to", &arg.to)
+        .memo(&arg.memo)
+        .ts(arg.created_at_time);
+
+    if let Some(duplicate_of) = request.duplicate_of() {
+        return Err(TransferError::Duplicate { duplicate_of });
+    }
 
-            HttpResponseBuilder::ok()
-                .header("Content-Type", "application/json; charset=utf-8")
-                .with_body_and_content_length(serde_json::to_string(&token).unwrap_or_default())
-                .build()
-        }
-        "/partition_details" => {
-            let list = with_stable_mem(|pm| pm.partition_details());
+    memo_size_check(config, &arg.memo).map_err(|message| TransferError::GenericError {
+        error_code: 0,
+        message,
+    })?;
 
-            HttpResponseBuilder::ok()
-                .header("Content-Type", "application/json; charset=utf-8")
-                .with_body_and_content_length(serde_json::to_string(&list).unwrap_or_default())
-                .build()
-        }
-        "/transfer_log" => {
-            let transfer_id = req.raw_query_param("id").unwrap();
-
-            let tx_logs = TRANSFER_LOG.with(|logs| {
-                let logs = logs.borrow();
-                match logs.get(transfer_id.parse::<u64>().unwrap_or_default()) {
-                    None => ic_cdk::trap("Invalid Transfer Id"),
-                    Some(log) => log,
-                }
-            });
+    let mut token = match pending.get(&arg.token_id) {
+        Some(token) => token.clone(),
+        None => TOKENS
+            .with(|tokens| tokens.borrow().get(&arg.token_id))
+            .ok_or(TransferError::NonExistingTokenId)?,
+    };
 
-            HttpResponseBuilder::ok()
-                .header("Content-Type", "application/json; charset=utf-8")
-                .with_body_and_content_length(serde_json::to_string(&tx_logs).unwrap_or_default())
-                .build()
-        }
-        _ => HttpResponseBuilder::not_found().build(),
+    if token.owner == arg.to {
+        return Err(TransferError::InvalidRecipient);
+    }
+
+    let permitted_time = current_time + config.permitted_drift;
+
+    if token.owner != *caller && !token.approval_check(permitted_time, caller) {
+        return Err(TransferError::Unauthorized);
     }
+
+    let block = BlockBuilder::new(BTYPE_TRANSFER)
+        .tid(arg.token_id)
+        .account("from", &token.owner)
+        .account("to", &arg.to)
+        .memo(&arg.memo)
+        .ts(arg.created_at_time)
+        .dedup_as(&request);
+
+    token.transfer(permitted_time, caller, arg.to.clone())?;
+
+    Ok((token, block))
 }
 
-#[update]
-pub fn update_config(arg: CollectionConfig) {
-    CONFIG.with(|c| {
-        let mut c = c.borrow_mut();
+/// Stores the token and recertifies its HTTP routes.
+fn save_token(token: Token) {
+    certification::token_changed(token.id);
+    insert_token(token);
+}
 
-        c.set(arg).unwrap();
-    });
+fn commit_transfer(current_time: u64, token: Token, block: BlockBuilder) -> u128 {
+    save_token(token);
+
+    block.log(current_time)
 }
 
-fn update_status(msg_id: u128, id: u128, expiry: u64, state: MintState) {
-    STATUS_MAP.with(|sm| {
-        let mut sm = sm.borrow_mut();
-        sm.insert(
-            msg_id,
-            MintStatus {
-                id,
-                amount: 1,
-                expiry,
-                state,
-            },
-        );
-    });
+fn transfer_token(
+    config: &CollectionConfig,
+    current_time: u64,
+    caller: Principal,
+    arg: &TransferArg,
+) -> TransferResult {
+    let caller = ICRCAccount::new(caller, arg.from_subaccount.clone());
+
+    let (token, block) = prepare_transfer(config, current_time, &caller, arg, &HashMap::new())?;
+
+    Ok(commit_transfer(current_time, token, block))
 }
 
 #[update]
-pub async fn mint_cknft(id: u128, chain_id: u64, target_eth_wallet: String) -> SelfMintArgs {
-    let caller = ic_cdk::caller();
-    let caller_subaccount = Subaccount::from(caller);
-
-    let nonce = NONCE_MAP.with(|nonce_map| {
-        let mut nonce_map = nonce_map.borrow_mut();
-        let nonce = nonce_map
-            .get(&caller_subaccount)
-            .unwrap_or(Nonce::zero())
-            .add_64(1);
+pub fn icrc7_approve(arg: ApprovalArgs) -> Result<u128, ApprovalError> {
+    let caller = ICRCAccount::new(ic_cdk::caller(), arg.from_subaccount);
 
-        nonce_map.insert(caller_subaccount.clone(), nonce);
-        nonce
-    });
+    let current_time = ic_cdk::api::time();
 
-    let msg_id = calc_msgid(&caller_subaccount, nonce);
     let config = get_icrc7_config();
-    let now = ic_cdk::api::time();
-    let expiry = now / 1_000_000_000 + config.tx_window;
 
-    update_status(msg_id, id, expiry, MintState::Init);
+    created_at_time_check(&config, current_time, arg.created_at_time)?;
 
-    // transfer CKNFT to this canister
-    let transfer_args = TransferArg {
-        to: ICRCAccount::from(ic_cdk::id()),
-        from_subaccount: Some(caller_subaccount),
-        token_id: id,
-        memo: None,
-        created_at_time: None,
+    let block = |btype| {
+        BlockBuilder::new(btype)
+            .account("from", &caller)
+            .account("spender", &arg.spender)
+            .exp(arg.expires_at)
+            .memo(&arg.memo)
+            .ts(arg.created_at_time)
     };
 
-    icrc7_transfer(transfer_args).unwrap();
+    let requests = match &arg.token_ids {
+        None => vec![block(BTYPE_APPROVE_COLLECTION)],
+        Some(token_ids) => token_ids
+            .iter()
+            .map(|id| block(BTYPE_APPROVE).tid(*id))
+            .collect(),
+    };
+    if let Some(duplicate_of) = requests.iter().find_map(BlockBuilder::duplicate_of) {
+        return Err(ApprovalError::Duplicate { duplicate_of });
+    }
 
-    // Generate tECDSA signature
-    // payload is (amount, to, msgId, expiry, chainId, cknft_eth_address), 32 bytes each
-    let cknft_eth_address = hex_string_with_0x_to_vec(&config.cknft_eth_address).unwrap();
-
-    let mut payload_to_sign: [u8; 192] = [0; 192];
-    payload_to_sign[24..32].copy_from_slice(&id.to_be_bytes());
-    payload_to_sign[44..64]
-        .copy_from_slice(&hex_string_with_0x_to_vec(&target_eth_wallet).unwrap());
-    payload_to_sign[80..96].copy_from_slice(&msg_id.to_be_bytes());
-    payload_to_sign[120..128].copy_from_slice(&expiry.to_be_bytes());
-    payload_to_sign[152..160].copy_from_slice(&chain_id.to_be_bytes());
-    payload_to_sign[172..192].copy_from_slice(&cknft_eth_address);
+    if let Some(expires_at) = arg.expires_at {
+        if expires_at < current_time {
+            return Err(ApprovalError::Expired {
+                ledger_time: current_time,
+            });
+        }
+    }
 
-    let hashed_payload = raw_keccak256(&payload_to_sign).to_vec();
+    memo_size_check(&config, &arg.memo)
+        .map_err(|msg| ApprovalError::GenericError { error_code: 0, msg })?;
 
-    let args = SignWithEcdsaArgument {
-        derivation_path: vec![],
-        message_hash: hashed_payload.clone(),
-        key_id: EcdsaKeyId {
-            curve: EcdsaCurve::Secp256k1,
-            name: config.ecdsa_key_name,
-        },
-    };
+    if arg.spender == caller {
+        return Err(ApprovalError::InvalidSpender);
+    }
 
-    let signature: Vec<u8> = {
-        let (res,): (SignWithEcdsaResponse,) = sign_with_ecdsa(args)
-            .await
-            .unwrap_or_else(|e| ic_cdk::trap(&format!("Failed to sign with ecdsa: {}", e.1)));
-        res.signature
-    };
+    let approval = Approval::new(arg.spender.clone(), arg.expires_at).with_memo(
+        arg.memo.clone(),
+        arg.created_at_time.unwrap_or(current_time),
+    );
 
-    let sec1_public_key = PUBLIC_KEY.with(|pk| pk.borrow().get().clone());
+    match arg.token_ids {
+        // collection-wide operator approval
+        None => {
+            approve_collection(&caller, approval)?;
 
-    let public_key = VerifyingKey::from_sec1_bytes(&sec1_public_key).unwrap();
+            Ok(block(BTYPE_APPROVE_COLLECTION).log(current_time))
+        }
+        Some(token_ids) => {
+            if token_ids.is_empty() {
+                return Err(ApprovalError::GenericError {
+                    error_code: 0,
+                    msg: "No token ids to approve".into(),
+                });
+            }
 
-    let recid = RecoveryId::trial_recovery_from_prehash(
-        &public_key,
-        &hashed_payload,
-        &Signature::from_slice(signature.as_slice()).unwrap(),
-    )
-    .unwrap();
+            let mut tokens = Vec::with_capacity(token_ids.len());
+            let mut unauthorized = vec![];
 
-    let v = recid.is_y_odd() as u8 + 27;
+            for id in token_ids {
+                let token = TOKENS
+                    .with(|tokens| tokens.borrow().get(&id))
+                    .ok_or(ApprovalError::NonExistingTokenId)?;
 
-    SIGNATURE_MAP.with(|sm| {
-        let mut sm = sm.borrow_mut();
-        sm.insert(msg_id, EcdsaSignature::from_signature_v(&signature, v));
-    });
+                if token.owner != caller {
+                    unauthorized.push(id);
+                }
+                tokens.push(token);
+            }
 
-    update_status(msg_id, id, expiry, MintState::Signed);
+            if !unauthorized.is_empty() {
+                return Err(ApprovalError::Unauthorized {
+                    tokens_ids: unauthorized,
+                });
+            }
 
-    // Return tECDSA signature
-    SelfMintArgs {
-        id,
-        to: target_eth_wallet,
-        msgid: msg_id,
-        expiry,
-        signature: EcdsaSignature::from_signature_v(&signature, v).to_string(),
+            let mut index = 0;
+            for mut token in tokens {
+                let id = token.id;
+                token.approve(&caller, approval.clone())?;
+
+                save_token(token);
+                index = block(BTYPE_APPROVE).tid(id).log(current_time);
+            }
+
+            Ok(index)
+        }
     }
 }
 
-#[update(guard = "caller_is_controller")]
-pub async fn update_cknft_state() -> Vec<u8> {
-    let config = get_icrc7_config();
+fn approve_token(
+    config: &CollectionConfig,
+    current_time: u64,
+    arg: ApproveTokenArg,
+) -> Result<u128, ApproveTokenError> {
+    let info = arg.approval_info;
+    let caller = ICRCAccount::new(ic_cdk::caller(), info.from_subaccount);
+
+    created_at_time_check(config, current_time, Some(info.created_at_time))?;
+
+    let block = BlockBuilder::new(BTYPE_APPROVE)
+        .tid(arg.token_id)
+        .account("from", &caller)
+        .account("spender", &info.spender)
+        .exp(info.expires_at)
+        .memo(&info.memo)
+        .ts(Some(info.created_at_time));
+
+    if let Some(duplicate_of) = block.duplicate_of() {
+        return Err(ApproveTokenError::Duplicate { duplicate_of });
+    }
 
-    let args = EcdsaPublicKeyArgument {
-        canister_id: None,
-        derivation_path: vec![],
-        key_id: EcdsaKeyId {
-            curve: EcdsaCurve::Secp256k1,
-            name: config.ecdsa_key_name,
-        },
-    };
+    memo_size_check(config, &info.memo).map_err(|message| ApproveTokenError::GenericError {
+        error_code: 0,
+        message,
+    })?;
 
-    // Update tecdsa signer key and calculate signer ETH address
-    let (res,): (EcdsaPublicKeyResponse,) = ecdsa_public_key(args)
-        .await
-        .unwrap_or_else(|_| ic_cdk::trap("Failed to get ecdsa public key"));
+    let mut token = TOKENS
+        .with(|tokens| tokens.borrow().get(&arg.token_id))
+        .ok_or(ApproveTokenError::NonExistingTokenId)?;
 
-    PUBLIC_KEY.with(|pk| {
-        let mut pk = pk.borrow_mut();
-        pk.set(res.public_key.clone()).unwrap();
-    });
+    if token.owner != caller {
+        return Err(ApproveTokenError::Unauthorized);
+    }
+    if info.spender == caller {
+        return Err(ApproveTokenError::InvalidSpender);
+    }
 
-    res.public_key
+    let approval =
+        Approval::new(info.spender, info.expires_at).with_memo(info.memo, info.created_at_time);
+
+    token.approve(&caller, approval).map_err(|err| match err {
+        ApprovalError::InvalidSpender => ApproveTokenError::InvalidSpender,
+        _ => ApproveTokenError::Unauthorized,
+    })?;
+
+    save_token(token);
+
+    Ok(block.log(current_time))
 }
 
-ic_cdk::export_candid!();
+#[update]
+pub fn icrc37_approve_tokens(
+    args: Vec<ApproveTokenArg>,
+) -> Vec<Option<Result<u128, ApproveTokenError>>> {
+    let config = get_icrc7_config();
+
+    if let Err(message) = update_batch_size_check(&config, args.len()) {
+        return vec![Some(Err(ApproveTokenError::GenericBatchError {
+            error_code: 0,
+            message,
+        }))];
+    }
+
+    let current_time = ic_cdk::api::time();
+
+    args.into_iter()
+        .map(|arg| Some(approve_token(&config, current_time, arg)))
+        .collect()
+}
+
+fn approve_collection_for(
+    config: &CollectionConfig,
+    current_time: u64,
+    arg: ApproveCollectionArg,
+) -> Result<u128, ApproveCollectionError> {
+    let info = arg.approval_info;
+    let caller = ICRCAccount::new(ic_cdk::caller(), info.from_subaccount);
+
+    created_at_time_check(config, current_time, Some(info.created_at_time))?;
+
+    let block = BlockBuilder::new(BTYPE_APPROVE_COLLECTION)
+        .account("from", &caller)
+        .account("spender", &info.spender)
+        .exp(info.expires_at)
+        .memo(&info.memo)
+        .ts(Some(info.created_at_time));
+
+    if let Some(duplicate_of) = block.duplicate_of() {
+        return Err(ApproveCollectionError::Duplicate { duplicate_of });
+    }
+
+    memo_size_check(config, &info.memo).map_err(|message| {
+        ApproveCollectionError::GenericError {
+            error_code: 0,
+            message,
+        }
+    })?;
+
+    if info.spender == caller {
+        return Err(ApproveCollectionError::InvalidSpender);
+    }
+
+    let approval =
+        Approval::new(info.spender, info.expires_at).with_memo(info.memo, info.created_at_time);
+
+    approve_collection(&caller, approval).map_err(|_| ApproveCollectionError::InvalidSpender)?;
+
+    Ok(block.log(current_time))
+}
+
+#[update]
+pub fn icrc37_approve_collection(
+    args: Vec<ApproveCollectionArg>,
+) -> Vec<Option<Result<u128, ApproveCollectionError>>> {
+    let config = get_icrc7_config();
+
+    if let Err(message) = update_batch_size_check(&config, args.len()) {
+        return vec![Some(Err(ApproveCollectionError::GenericBatchError {
+            error_code: 0,
+            message,
+        }))];
+    }
+
+    let current_time = ic_cdk::api::time();
+
+    args.into_iter()
+        .map(|arg| Some(approve_collection_for(&config, current_time, arg)))
+        .collect()
+}
+
+fn revoke_token_approvals(
+    config: &CollectionConfig,
+    current_time: u64,
+    arg: RevokeTokenApprovalArg,
+) -> Result<u128, RevokeTokenApprovalError> {
+    let caller = ICRCAccount::new(ic_cdk::caller(), arg.from_subaccount);
+
+    created_at_time_check(config, current_time, arg.created_at_time)?;
+
+    let block = BlockBuilder::new(BTYPE_REVOKE)
+        .tid(arg.token_id)
+        .account("from", &caller)
+        .opt_account("spender", arg.spender.as_ref())
+        .memo(&arg.memo)
+        .ts(arg.created_at_time);
+
+    if let Some(duplicate_of) = block.duplicate_of() {
+        return Err(RevokeTokenApprovalError::Duplicate { duplicate_of });
+    }
+
+    memo_size_check(config, &arg.memo).map_err(|message| {
+        RevokeTokenApprovalError::GenericError {
+            error_code: 0,
+            message,
+        }
+    })?;
+
+    let mut token = TOKENS
+        .with(|tokens| tokens.borrow().get(&arg.token_id))
+        .ok_or(RevokeTokenApprovalError::NonExistingTokenId)?;
+
+    if token.owner != caller {
+        return Err(RevokeTokenApprovalError::Unauthorized);
+    }
+
+    if !token.revoke(arg.spender.as_ref()) {
+        return Err(RevokeTokenApprovalError::ApprovalDoesNotExist);
+    }
+
+    save_token(token);
+
+    Ok(block.log(current_time))
+}
+
+#[update]
+pub fn icrc37_revoke_token_approvals(
+    args: Vec<RevokeTokenApprovalArg>,
+) -> Vec<Option<Result<u128, RevokeTokenApprovalError>>> {
+    let config = get_icrc7_config();
+
+    if let Err(message) = update_batch_size_check(&config, args.len()) {
+        return vec![Some(Err(RevokeTokenApprovalError::GenericBatchError {
+            error_code: 0,
+            message,
+        }))];
+    }
+
+    let current_time = ic_cdk::api::time();
+
+    args.into_iter()
+        .map(|arg| Some(revoke_token_approvals(&config, current_time, arg)))
+        .collect()
+}
+
+fn revoke_collection_approvals_for(
+    config: &CollectionConfig,
+    current_time: u64,
+    arg: RevokeCollectionApprovalArg,
+) -> Result<u128, RevokeCollectionApprovalError> {
+    let caller = ICRCAccount::new(ic_cdk::caller(), arg.from_subaccount);
+
+    created_at_time_check(config, current_time, arg.created_at_time)?;
+
+    let block = BlockBuilder::new(BTYPE_REVOKE_COLLECTION)
+        .account("from", &caller)
+        .opt_account("spender", arg.spender.as_ref())
+        .memo(&arg.memo)
+        .ts(arg.created_at_time);
+
+    if let Some(duplicate_of) = block.duplicate_of() {
+        return Err(RevokeCollectionApprovalError::Duplicate { duplicate_of });
+    }
+
+    memo_size_check(config, &arg.memo).map_err(|message| {
+        RevokeCollectionApprovalError::GenericError {
+            error_code: 0,
+            message,
+        }
+    })?;
+
+    if !revoke_collection_approvals(&caller, arg.spender.as_ref()) {
+        return Err(RevokeCollectionApprovalError::ApprovalDoesNotExist);
+    }
+
+    Ok(block.log(current_time))
+}
+
+#[update]
+pub fn icrc37_revoke_collection_approvals(
+    args: Vec<RevokeCollectionApprovalArg>,
+) -> Vec<Option<Result<u128, RevokeCollectionApprovalError>>> {
+    let config = get_icrc7_config();
+
+    if let Err(message) = update_batch_size_check(&config, args.len()) {
+        return vec![Some(Err(
+            RevokeCollectionApprovalError::GenericBatchError {
+                error_code: 0,
+                message,
+            },
+        ))];
+    }
+
+    let current_time = ic_cdk::api::time();
+
+    args.into_iter()
+        .map(|arg| Some(revoke_collection_approvals_for(&config, current_time, arg)))
+        .collect()
+}
+
+fn transfer_from(
+    config: &CollectionConfig,
+    current_time: u64,
+    arg: TransferFromArg,
+) -> Result<u128, TransferFromError> {
+    let spender = ICRCAccount::new(ic_cdk::caller(), arg.spender_subaccount);
+
+    created_at_time_check(config, current_time, arg.created_at_time)?;
+
+    let block = BlockBuilder::new(BTYPE_TRANSFER_FROM)
+        .tid(arg.token_id)
+        .account("spender", &spender)
+        .account("from", &arg.from)
+        .account("to", &arg.to)
+        .memo(&arg.memo)
+        .ts(arg.created_at_time);
+
+    if let Some(duplicate_of) = block.duplicate_of() {
+        return Err(TransferFromError::Duplicate { duplicate_of });
+    }
+
+    memo_size_check(config, &arg.memo).map_err(|message| TransferFromError::GenericError {
+        error_code: 0,
+        message,
+    })?;
+
+    let mut token = TOKENS
+        .with(|tokens| tokens.borrow().get(&arg.token_id))
+        .ok_or(TransferFromError::NonExistingTokenId)?;
+
+    if arg.from == arg.to {
+        return Err(TransferFromError::InvalidRecipient);
+    }
+
+    let permitted_time = current_time + config.permitted_drift;
+
+    if token.owner != arg.from || !token.approval_check(permitted_time, &spender) {
+        return Err(TransferFromError::Unauthorized);
+    }
+
+    token
+        .transfer(permitted_time, &spender, arg.to.clone())
+        .map_err(|_| TransferFromError::Unauthorized)?;
+
+    save_token(token);
+
+    Ok(block.log(current_time))
+}
+
+#[update(guard = "not_paused")]
+pub fn icrc37_transfer_from(
+    args: Vec<TransferFromArg>,
+) -> Vec<Option<Result<u128, TransferFromError>>> {
+    let config = get_icrc7_config();
+
+    if let Err(message) = update_batch_size_check(&config, args.len()) {
+        return vec![Some(Err(TransferFromError::GenericBatchError {
+            error_code: 0,
+            message,
+        }))];
+    }
+
+    let current_time = ic_cdk::api::time();
+
+    args.into_iter()
+        .map(|arg| Some(transfer_from(&config, current_time, arg)))
+        .collect()
+}
+
+/// Validates `arg` against the current state, overlaid by the `pending` ids of the same batch.
+/// The caller is checked to be a minter by the endpoints.
+fn mint_token(
+    config: &CollectionConfig,
+    caller: Principal,
+    arg: MintArgs,
+    pending: &BTreeSet<u128>,
+) -> Result<(Token, BlockBuilder), MintError> {
+    if arg.to.owner() == Principal::anonymous() || Some(&arg.to) == config.burn_account.as_ref() {
+        return Err(MintError::InvalidRecipient);
+    }
+
+    if let Some(cap) = config.supply_cap {
+        if get_total_supply() + pending.len() as u128 >= cap {
+            return Err(MintError::SupplyCapReached);
+        }
+    }
+
+    let id = arg.id.unwrap_or_else(|| {
+        let next_pending = pending.last().map_or(0, |id| id.saturating_add(1));
+
+        next_token_id().max(next_pending)
+    });
+
+    if pending.contains(&id) || TOKENS.with(|tokens| tokens.borrow().contains_key(&id)) {
+        return Err(MintError::TokenIdExists);
+    }
+
+    if is_burned_id(id) {
+        return Err(MintError::TokenIdBurned);
+    }
+
+    if arg
+        .asset_id
+        .is_some_and(|asset_id| !is_committed_by(asset_id, &caller))
+    {
+        return Err(MintError::AssetNotFound);
+    }
+
+    let block = BlockBuilder::new(BTYPE_MINT).tid(id).account("to", &arg.to);
+
+    let token = Token {
+        id,
+        name: arg.name,
+        description: arg.description,
+        // an inline image is only stored as an asset once the mint commits
+        image: arg.image.filter(|_| arg.asset_id.is_none()),
+        owner: arg.to,
+        approvals: Vec::new(),
+        asset_id: arg.asset_id,
+    };
+
+    Ok((token, block))
+}
+
+fn commit_mint(
+    current_time: u64,
+    caller: Principal,
+    mut token: Token,
+    block: BlockBuilder,
+) -> u128 {
+    if let Some(image) = token.image.take() {
+        let asset_id = create_asset(caller, DEFAULT_CONTENT_TYPE.into(), image);
+        token.asset_id = Some(asset_id);
+    }
+
+    increment_total_supply();
+    save_token(token);
+
+    block.log(current_time)
+}
+
+#[update(guard = "caller_is_minter")]
+pub fn begin_asset_upload(arg: BeginAssetUploadArg) -> u64 {
+    assets::begin_upload(ic_cdk::caller(), arg.content_type, ic_cdk::api::time())
+}
+
+#[update(guard = "caller_is_minter")]
+pub fn put_asset_chunk(arg: PutAssetChunkArg) -> Result<(), AssetError> {
+    assets::put_chunk(&ic_cdk::caller(), arg)
+}
+
+#[update(guard = "caller_is_minter")]
+pub fn commit_asset_upload(arg: CommitAssetUploadArg) -> Result<AssetInfo, AssetError> {
+    let info = assets::commit_upload(&ic_cdk::caller(), arg)?;
+    update_certified_data();
+
+    Ok(info)
+}
+
+#[query]
+pub fn asset_info(asset_id: u64) -> Option<AssetInfo> {
+    assets::asset_info(asset_id)
+}
+
+#[update(guard = "not_paused")]
+pub fn icrc7_mint(arg: MintArgs) -> MintResult {
+    if caller_is_minter().is_err() {
+        return Err(MintError::Unauthorized);
+    }
+
+    let config = get_icrc7_config();
+    let caller = ic_cdk::caller();
+    let (token, block) = mint_token(&config, caller, arg, &BTreeSet::new())?;
+
+    Ok(commit_mint(ic_cdk::api::time(), caller, token, block))
+}
+
+#[update(guard = "not_paused")]
+pub fn icrc7_mint_batch(args: Vec<MintArgs>) -> Vec<Option<MintResult>> {
+    let config = get_icrc7_config();
+
+    if let Err(message) = update_batch_size_check(&config, args.len()) {
+        return vec![Some(Err(MintError::GenericBatchError {
+            error_code: 0,
+            message,
+        }))];
+    }
+
+    if caller_is_minter().is_err() {
+        return vec![Some(Err(MintError::Unauthorized)); args.len()];
+    }
+
+    mint_batch(&config, ic_cdk::api::time(), ic_cdk::caller(), args)
+}
+
+fn mint_batch(
+    config: &CollectionConfig,
+    current_time: u64,
+    caller: Principal,
+    args: Vec<MintArgs>,
+) -> Vec<Option<MintResult>> {
+    if !config.atomic_batch_mints.unwrap_or(false) {
+        return args
+            .into_iter()
+            .map(|arg| {
+                let (token, block) = mint_token(config, caller, arg, &BTreeSet::new())?;
+
+                Ok(commit_mint(current_time, caller, token, block))
+            })
+            .map(Some)
+            .collect();
+    }
+
+    // atomic mode: validate the whole drop first and only commit if every item passes
+    let mut pending = BTreeSet::new();
+    let mut prepared = Vec::with_capacity(args.len());
+    let mut results: Vec<Option<MintResult>> = vec![None; args.len()];
+    let count = args.len();
+
+    for (index, arg) in args.into_iter().enumerate() {
+        match mint_token(config, caller, arg, &pending) {
+            Ok((token, block)) => {
+                pending.insert(token.id);
+                prepared.push((index, token, block));
+            }
+            Err(e) => results[index] = Some(Err(e)),
+        }
+    }
+
+    if prepared.len() != count {
+        return results
+            .into_iter()
+            .map(|result| {
+                result.or(Some(Err(MintError::GenericBatchError {
+                    error_code: 1,
+                    message: "Atomic batch mint aborted".into(),
+                })))
+            })
+            .collect();
+    }
+
+    for (index, token, block) in prepared {
+        results[index] = Some(Ok(commit_mint(current_time, caller, token, block)));
+    }
+
+    results
+}
+
+fn burn_token(
+    config: &CollectionConfig,
+    current_time: u64,
+    caller: Principal,
+    arg: &BurnArg,
+) -> BurnResult {
+    let caller = ICRCAccount::new(caller, arg.from_subaccount.clone());
+
+    created_at_time_check(config, current_time, arg.created_at_time)?;
+
+    let request = BlockBuilder::new(BTYPE_BURN)
+        .tid(arg.token_id)
+        .account("from", &caller)
+        .memo(&arg.memo)
+        .ts(arg.created_at_time);
+
+    if let Some(duplicate_of) = request.duplicate_of() {
+        return Err(BurnError::Duplicate { duplicate_of });
+    }
+
+    memo_size_check(config, &arg.memo).map_err(|message| BurnError::GenericError {
+        error_code: 0,
+        message,
+    })?;
+
+    let token = TOKENS
+        .with(|tokens| tokens.borrow().get(&arg.token_id))
+        .ok_or(BurnError::NonExistingTokenId)?;
+
+    let permitted_time = current_time + config.permitted_drift;
+
+    if token.owner != caller && !token.approval_check(permitted_time, &caller) {
+        return Err(BurnError::Unauthorized);
+    }
+
+    // the token approvals go away together with the entry
+    remove_token(arg.token_id);
+    certification::token_changed(arg.token_id);
+    decrement_total_supply();
+
+    if config.burn_account.is_some() {
+        BURNED_IDS.with(|ids| ids.borrow_mut().insert(arg.token_id, ()));
+    }
+
+    let block = BlockBuilder::new(BTYPE_BURN)
+        .tid(arg.token_id)
+        .account("from", &token.owner)
+        .opt_account("to", config.burn_account.as_ref())
+        .memo(&arg.memo)
+        .ts(arg.created_at_time)
+        .dedup_as(&request);
+
+    Ok(block.log(current_time))
+}
+
+#[update(guard = "not_paused")]
+pub fn icrc7_burn(arg: BurnArg) -> BurnResult {
+    let config = get_icrc7_config();
+
+    burn_token(&config, ic_cdk::api::time(), ic_cdk::caller(), &arg)
+}
+
+#[update(guard = "not_paused")]
+pub fn icrc7_burn_batch(args: Vec<BurnArg>) -> Vec<Option<BurnResult>> {
+    let config = get_icrc7_config();
+
+    if let Err(message) = update_batch_size_check(&config, args.len()) {
+        return vec![Some(Err(BurnError::GenericBatchError {
+            error_code: 0,
+            message,
+        }))];
+    }
+
+    let current_time = ic_cdk::api::time();
+    let caller = ic_cdk::caller();
+
+    args.iter()
+        .map(|arg| Some(burn_token(&config, current_time, caller, arg)))
+        .collect()
+}
+
+#[query]
+fn http_request(req: HttpRequest) -> HttpResponse {
+    let response = http::handle(&req);
+
+    certification::certify_response(&req, response)
+}
+
+#[query]
+fn http_request_streaming_callback(token: StreamingCallbackToken) -> StreamingCallbackHttpResponse {
+    http::streaming_callback(token)
+}
+
+#[update(guard = "caller_is_config_admin")]
+pub fn update_config(arg: CollectionConfig) {
+    // names and chain ids are rendered into the certified responses
+    let rendered = |config: &CollectionConfig| {
+        (
+            config.name.clone(),
+            config.description.clone(),
+            config.evm_chain_ids.clone(),
+        )
+    };
+    let changed = rendered(&get_icrc7_config()) != rendered(&arg);
+
+    CONFIG.with(|c| {
+        let mut c = c.borrow_mut();
+
+        c.set(arg).unwrap();
+    });
+
+    if changed {
+        certify_all();
+    }
+}
+
+/// Module installed on archive canisters spawned by the block log.
+#[update(guard = "caller_is_owner")]
+pub fn set_archive_wasm(wasm: ByteBuf) {
+    ARCHIVE_WASM.with(|w| w.borrow_mut().set(wasm.into_vec()).unwrap());
+}
+
+#[update(guard = "caller_is_owner")]
+pub fn grant_role(role: Role, principal: Principal) -> bool {
+    let granted = state::grant_role(role, principal);
+
+    if granted && role == Role::Minter {
+        log_minters(ic_cdk::api::time());
+    }
+
+    granted
+}
+
+#[update(guard = "caller_is_owner")]
+pub fn revoke_role(role: Role, principal: Principal) -> bool {
+    let revoked = state::revoke_role(role, principal);
+
+    if revoked && role == Role::Minter {
+        log_minters(ic_cdk::api::time());
+    }
+
+    revoked
+}
+
+/// Stops transfers, mints, burns and bridge calls, returns whether the canister was running.
+#[update(guard = "caller_is_pauser")]
+pub fn pause() -> bool {
+    state::set_paused(true)
+}
+
+#[update(guard = "caller_is_pauser")]
+pub fn unpause() -> bool {
+    state::set_paused(false)
+}
+
+#[query]
+pub fn is_paused() -> bool {
+    state::is_paused()
+}
+
+/// Replaces every minting authority, principals may be users or canisters such as a launchpad.
+#[update(guard = "caller_is_owner")]
+pub fn set_minting_authorities(minters: Vec<Principal>) -> u128 {
+    for minter in role_holders(Role::Minter) {
+        state::revoke_role(Role::Minter, minter);
+    }
+
+    for minter in minters {
+        state::grant_role(Role::Minter, minter);
+    }
+
+    log_minters(ic_cdk::api::time())
+}
+
+#[query]
+pub fn icrc7_minting_authorities() -> Vec<Principal> {
+    role_holders(Role::Minter)
+}
+
+fn log_minters(current_time: u64) -> u128 {
+    BlockBuilder::new(BTYPE_MINTERS)
+        .principal("caller", &ic_cdk::caller())
+        .principals("minters", &role_holders(Role::Minter))
+        .log(current_time)
+}
+
+#[query]
+pub fn list_roles(role: Option<Role>) -> Vec<RoleAssignment> {
+    role_assignments(role)
+}
+
+/// Nanoseconds between two RPC backed calls of a caller that attaches no cycles.
+const RPC_CALL_COOLDOWN: u64 = 60 * 1_000_000_000;
+/// Callers without cycles served per cooldown across the canister, bounding what any number
+/// of principals can spend on outcalls.
+const MAX_UNPAID_RPC_CALLERS: usize = 20;
+
+/// Outcalls are paid by the canister, callers either attach the `cycles` of the outcalls they
+/// cause or are rate limited, bridge operators are not. Returns the seconds to wait otherwise.
+fn charge_rpc_call(cycles: u128) -> Result<(), u64> {
+    if caller_is_bridge_operator().is_ok() {
+        return Ok(());
+    }
+
+    if ic_cdk::api::call::msg_cycles_available128() >= cycles {
+        ic_cdk::api::call::msg_cycles_accept128(cycles);
+        return Ok(());
+    }
+
+    let caller = ic_cdk::caller();
+    let now = ic_cdk::api::time();
+    let retry_after = |at: u64| (at + RPC_CALL_COOLDOWN - now).div_ceil(1_000_000_000);
+
+    UNPAID_RPC_CALLS.with(|calls| {
+        let mut calls = calls.borrow_mut();
+        calls.retain(|_, at| *at + RPC_CALL_COOLDOWN > now);
+
+        if let Some(at) = calls.get(&caller) {
+            return Err(retry_after(*at));
+        }
+        if calls.len() >= MAX_UNPAID_RPC_CALLERS {
+            return Err(retry_after(*calls.values().min().unwrap()));
+        }
+
+        calls.insert(caller, now);
+        Ok(())
+    })
+}
+
+/// Releases the custody tokens burned on EVM by `burnToCkNFT` in `tx_hash`, each log once.
+/// Anyone may submit the transaction, tokens only go to the accounts encoded in the logs.
+#[update(guard = "not_paused")]
+pub async fn withdraw_from_evm(arg: WithdrawArg) -> Result<Vec<BurnWithdrawal>, WithdrawError> {
+    if ic_cdk::caller() == Principal::anonymous() {
+        return Err(WithdrawError::AnonymousCaller);
+    }
+
+    let config = get_icrc7_config();
+
+    let urls = config.evm_rpc_urls(arg.chain_id);
+
+    if urls.is_empty() {
+        return Err(WithdrawError::NoRpcProvider {
+            chain_id: arg.chain_id,
+        });
+    }
+
+    let tx_hash: [u8; 32] = hex_string_with_0x_to_vec(&arg.tx_hash)
+        .ok()
+        .and_then(|hash| hash.try_into().ok())
+        .ok_or(WithdrawError::InvalidTransactionHash)?;
+
+    // the receipt and the block number from every provider
+    let cycles =
+        outcall_cycles("eth_getTransactionReceipt") + outcall_cycles("eth_getBlockByNumber");
+    charge_rpc_call(urls.len() as u128 * cycles)
+        .map_err(|retry_after| WithdrawError::RateLimited { retry_after })?;
+
+    let rpc = HttpsEvmRpc::https(urls, config.evm_rpc_consensus.clone().unwrap_or_default());
+
+    let events = bridge::confirmed_burn_events(
+        &rpc,
+        &config.cknft_eth_address,
+        config.evm_min_confirmations(),
+        &arg.tx_hash,
+    )
+    .await?;
+
+    // nothing is awaited from here on, so concurrent calls cannot release a burn twice
+    let config = get_icrc7_config();
+    let current_time = ic_cdk::api::time();
+
+    let withdrawals = events
+        .into_iter()
+        .map(|event| {
+            let key = BurnEventKey {
+                chain_id: arg.chain_id,
+                tx_hash,
+                log_index: event.log_index,
+            };
+
+            BurnWithdrawal {
+                log_index: event.log_index,
+                token_id: event.token_id,
+                to: event.to.clone(),
+                result: release_burned_token(&config, current_time, key, &event),
+            }
+        })
+        .collect();
+
+    Ok(withdrawals)
+}
+
+fn release_burned_token(
+    config: &CollectionConfig,
+    current_time: u64,
+    key: BurnEventKey,
+    event: &BurnEvent,
+) -> TransferResult {
+    if let Some(index) = processed_burn(&key) {
+        return Err(TransferError::Duplicate {
+            duplicate_of: index,
+        });
+    }
+
+    let custody = ICRCAccount::from(ic_cdk::id());
+
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: event.to.clone(),
+        token_id: event.token_id,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let (token, block) = prepare_transfer(config, current_time, &custody, &arg, &HashMap::new())?;
+    let index = commit_transfer(current_time, token, block);
+
+    insert_processed_burn(key, index);
+
+    Ok(index)
+}
+
+#[query]
+fn transform_rpc_response(args: TransformArgs) -> OutcallHttpResponse {
+    evm_rpc::transform_rpc_response(args)
+}
+
+fn update_status(msg_id: u128, status: MintStatus) {
+    state::index_pending_mint(msg_id, &status);
+
+    STATUS_MAP.with(|sm| {
+        let mut sm = sm.borrow_mut();
+        sm.insert(msg_id, status);
+    });
+}
+
+fn set_mint_state(msg_id: u128, state: MintState) {
+    if let Some(status) = get_mint_status(msg_id) {
+        update_status(msg_id, MintStatus { state, ..status });
+    }
+}
+
+#[query]
+pub fn get_mint_status(msg_id: u128) -> Option<MintStatus> {
+    STATUS_MAP.with(|sm| sm.borrow().get(&msg_id))
+}
+
+/// Bridge requests of `caller`, newest first, starting after the message id `prev`.
+/// Message ids derive from the caller's nonce, so they are recomputed instead of indexed.
+#[query]
+pub fn list_mint_status(
+    caller: Principal,
+    prev: Option<u128>,
+    take: Option<u128>,
+) -> Vec<(u128, MintStatus)> {
+    let config = get_icrc7_config();
+
+    mint_statuses_of(caller, prev, take_value(&config, take))
+}
+
+fn mint_statuses_of(caller: Principal, prev: Option<u128>, take: usize) -> Vec<(u128, MintStatus)> {
+    let caller_subaccount = Subaccount::from(caller);
+
+    let last_nonce = NONCE_MAP
+        .with(|nonce_map| nonce_map.borrow().get(&caller_subaccount))
+        .map_or(0, |nonce| nonce.get());
+
+    let mut msg_ids = (1..=last_nonce)
+        .rev()
+        .map(|nonce| calc_msgid(&caller_subaccount, Nonce::new(Some(nonce))));
+
+    if let Some(prev) = prev {
+        if !msg_ids.any(|msg_id| msg_id == prev) {
+            return vec![];
+        }
+    }
+
+    msg_ids
+        .filter_map(|msg_id| get_mint_status(msg_id).map(|status| (msg_id, status)))
+        .take(take)
+        .collect()
+}
+
+/// Checks a pending entry right away instead of waiting for the next timer run.
+#[update(guard = "not_paused")]
+pub async fn refresh_mint_status(msg_id: u128) -> Result<MintStatus, String> {
+    let status = get_mint_status(msg_id).ok_or("Unknown message id")?;
+
+    let is_owner = status
+        .owner
+        .as_ref()
+        .is_some_and(|owner| owner.owner() == ic_cdk::caller());
+
+    if !is_owner {
+        caller_is_bridge_operator()?;
+    }
+
+    if let Some(chain_id) = status.chain_id.filter(|_| status.is_pending()) {
+        let providers = get_icrc7_config().evm_rpc_urls(chain_id).len() as u128;
+
+        // the finalized block and the log ranges from every provider
+        let cycles = outcall_cycles("eth_getBlockByNumber")
+            + bridge::LOG_RANGES_PER_CHECK as u128 * outcall_cycles("eth_getLogs");
+        charge_rpc_call(providers * cycles)
+            .map_err(|retry_after| format!("Rate limited, retry in {} seconds", retry_after))?;
+    }
+
+    check_mint_status(msg_id, status).await
+}
+
+/// Timers do not survive upgrades, so this runs from both `init` and `post_upgrade`.
+fn start_mint_status_checks() {
+    ic_cdk_timers::set_timer_interval(
+        MINT_STATUS_INTERVAL,
+        || ic_cdk::spawn(check_pending_mints()),
+    );
+}
+
+async fn check_pending_mints() {
+    // refunds move tokens, they wait for the canister to resume
+    if state::is_paused() {
+        return;
+    }
+
+    let _guard = match CheckingMintsGuard::acquire() {
+        Some(guard) => guard,
+        None => return,
+    };
+
+    for (msg_id, _) in state::pending_mints() {
+        // settled by a call while earlier entries were checked
+        let status = match get_mint_status(msg_id) {
+            Some(status) if status.is_pending() => status,
+            _ => continue,
+        };
+
+        if let Err(err) = check_mint_status(msg_id, status).await {
+            ic_cdk::println!("Checking mint {} failed: {}", msg_id, err);
+        }
+    }
+}
+
+async fn check_mint_status(msg_id: u128, status: MintStatus) -> Result<MintStatus, String> {
+    // entries of older versions do not know their chain
+    let chain_id = match status.chain_id {
+        Some(chain_id) if status.is_pending() => chain_id,
+        _ => return Ok(status),
+    };
+
+    let config = get_icrc7_config();
+    let rpc = HttpsEvmRpc::https(
+        config.evm_rpc_urls(chain_id),
+        config.evm_rpc_consensus.clone().unwrap_or_default(),
+    );
+    let now = ic_cdk::api::time() / 1_000_000_000;
+
+    let checked =
+        bridge::check_mint_status(&rpc, &config.cknft_eth_address, msg_id, &status, now).await?;
+
+    // the entry may have moved on while the providers were queried
+    let current = get_mint_status(msg_id).ok_or("Unknown message id")?;
+    if current.state != status.state || BridgeLock::is_locked(current.id) {
+        return Ok(current);
+    }
+
+    if checked.state == MintState::Expired {
+        // the canister may have been paused while the providers were queried
+        if state::is_paused() {
+            return Ok(current);
+        }
+
+        let custody = ICRCAccount::from(ic_cdk::id());
+        let (expired, refund) = expire_mint(msg_id, current, &custody, ic_cdk::api::time());
+
+        if let Err(err) = refund {
+            ic_cdk::println!("Refund of mint {} failed: {:?}", msg_id, err);
+        }
+
+        return Ok(expired);
+    }
+
+    update_status(msg_id, checked.clone());
+
+    Ok(checked)
+}
+
+/// Attests that `SelfMint(msg_id)` was never emitted on EVM, for entries the providers cannot
+/// check, those of older versions included. `owner` is the refund account of entries that did
+/// not record it.
+#[update(guard = "caller_is_bridge_operator")]
+pub fn attest_mint_expired(msg_id: u128, owner: Option<ICRCAccount>) -> TransferResult {
+    let error = |message: &str| TransferError::GenericError {
+        error_code: 0,
+        message: message.into(),
+    };
+
+    // refunds move tokens, they wait for the canister to resume
+    if state::is_paused() {
+        return Err(error("Canister is paused"));
+    }
+
+    let status = get_mint_status(msg_id).ok_or(error("Unknown message id"))?;
+
+    if !status.is_pending() {
+        return Err(error("Mint is already settled"));
+    }
+
+    if BridgeLock::is_locked(status.id) {
+        return Err(error("Token has a bridge call in progress"));
+    }
+
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    if now < status.expiry.saturating_add(EXPIRY_GRACE_PERIOD) {
+        return Err(error("Signature has not expired"));
+    }
+
+    let status = MintStatus {
+        owner: status.owner.or(owner),
+        ..status
+    };
+
+    let custody = ICRCAccount::from(ic_cdk::id());
+
+    expire_mint(msg_id, status, &custody, ic_cdk::api::time()).1
+}
+
+/// Returns the token from custody to the account it came from and marks the entry expired,
+/// an entry whose refund fails is left `Refunding` for the next check or an attestation.
+fn expire_mint(
+    msg_id: u128,
+    status: MintStatus,
+    custody: &ICRCAccount,
+    current_time: u64,
+) -> (MintStatus, TransferResult) {
+    let error = |message: &str| TransferError::GenericError {
+        error_code: 0,
+        message: message.into(),
+    };
+
+    // the token never left its owner
+    if status.state == MintState::Init {
+        let status = MintStatus {
+            state: MintState::Expired,
+            ..status
+        };
+        update_status(msg_id, status.clone());
+
+        return (status, Err(error("Token was never taken into custody")));
+    }
+
+    let refund = refund_custody_token(msg_id, &status, custody, current_time);
+
+    let status = MintStatus {
+        state: if refund.is_ok() {
+            MintState::Expired
+        } else {
+            MintState::Refunding
+        },
+        ..status
+    };
+    update_status(msg_id, status.clone());
+
+    (status, refund)
+}
+
+fn refund_custody_token(
+    msg_id: u128,
+    status: &MintStatus,
+    custody: &ICRCAccount,
+    current_time: u64,
+) -> TransferResult {
+    let error = |message: &str| TransferError::GenericError {
+        error_code: 0,
+        message: message.into(),
+    };
+
+    let owner = status.owner.clone().ok_or(error("Unknown owner"))?;
+
+    // a newer request may hold the same token in custody
+    let held = state::pending_mints()
+        .into_iter()
+        .any(|(other, id)| other != msg_id && id == status.id);
+    if held {
+        return Err(error("Token is held for a pending request"));
+    }
+
+    let config = get_icrc7_config();
+
+    let arg = TransferArg {
+        from_subaccount: None,
+        to: owner,
+        token_id: status.id,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let (token, block) = prepare_transfer(&config, current_time, custody, &arg, &HashMap::new())?;
+
+    Ok(commit_transfer(current_time, token, block))
+}
+
+/// Moves the token into custody and signs its `selfMint` on EVM, each step is recorded in
+/// `STATUS_MAP` so that `retry_mint_signature` can resume a call that failed to sign.
+#[update(guard = "not_paused")]
+pub async fn mint_cknft(id: u128, chain_id: u64, target_eth_wallet: String) -> SelfMintArgs {
+    let _lock = BridgeLock::acquire(id).unwrap_or_else(|err| ic_cdk::trap(&err));
+
+    if eth_address(&target_eth_wallet).is_none() {
+        ic_cdk::trap("Invalid target wallet");
+    }
+
+    let config = get_icrc7_config();
+    charge_signing(&config, chain_id).unwrap_or_else(|err| ic_cdk::trap(&err));
+
+    let caller = ic_cdk::caller();
+    let caller_subaccount = Subaccount::from(caller);
+
+    let nonce = NONCE_MAP.with(|nonce_map| {
+        let mut nonce_map = nonce_map.borrow_mut();
+        let nonce = nonce_map
+            .get(&caller_subaccount)
+            .unwrap_or(Nonce::zero())
+            .add_64(1);
+
+        nonce_map.insert(caller_subaccount.clone(), nonce);
+        nonce
+    });
+
+    let msg_id = calc_msgid(&caller_subaccount, nonce);
+    let expiry = ic_cdk::api::time() / 1_000_000_000 + config.tx_window;
+
+    update_status(
+        msg_id,
+        MintStatus {
+            id,
+            amount: 1,
+            expiry,
+            state: MintState::Init,
+            owner: Some(ICRCAccount::new(caller, Some(caller_subaccount))),
+            chain_id: Some(chain_id),
+            from_block: None,
+            to: Some(target_eth_wallet),
+        },
+    );
+
+    let custody = ICRCAccount::from(ic_cdk::id());
+    take_custody(msg_id, &custody, ic_cdk::api::time())
+        .unwrap_or_else(|err| ic_cdk::trap(&format!("{:?}", err)));
+
+    sign_mint(msg_id)
+        .await
+        .unwrap_or_else(|err| ic_cdk::trap(&err))
+}
+
+/// Resumes a request from its recorded state, signing again a request whose signing failed
+/// and returning the stored signature once signed.
+#[update(guard = "not_paused")]
+pub async fn retry_mint_signature(msg_id: u128) -> Result<SelfMintArgs, String> {
+    let status = get_mint_status(msg_id).ok_or("Unknown message id")?;
+
+    let is_owner = status
+        .owner
+        .as_ref()
+        .is_some_and(|owner| owner.owner() == ic_cdk::caller());
+
+    if !is_owner {
+        caller_is_bridge_operator()?;
+    }
+
+    let _lock = BridgeLock::acquire(status.id)?;
+
+    let now = ic_cdk::api::time() / 1_000_000_000;
+    if let Some(signed) = resumable_mint(msg_id, &status, now)? {
+        return Ok(signed);
+    }
+
+    if let Some(chain_id) = status.chain_id {
+        charge_signing(&get_icrc7_config(), chain_id)?;
+    }
+
+    if status.state == MintState::Init {
+        // only the owner can hand the token over
+        if !is_owner {
+            return Err("Only the owner can move the token into custody".into());
+        }
+
+        let custody = ICRCAccount::from(ic_cdk::id());
+        take_custody(msg_id, &custody, ic_cdk::api::time()).map_err(|err| format!("{:?}", err))?;
+    }
+
+    sign_mint(msg_id).await
+}
+
+/// The stored signature of a signed request, `None` for a request that still has to be signed.
+fn resumable_mint(
+    msg_id: u128,
+    status: &MintStatus,
+    now_secs: u64,
+) -> Result<Option<SelfMintArgs>, String> {
+    if status.state == MintState::Signed {
+        return signed_mint(msg_id, status)
+            .map(Some)
+            .ok_or("Signature not found".into());
+    }
+
+    if !status.is_pending() {
+        return Err("Mint is already settled".into());
+    }
+
+    if now_secs >= status.expiry {
+        return Err("Signature has expired".into());
+    }
+
+    Ok(None)
+}
+
+/// Signing reads the finalized block from every provider of `chain_id`, which has to be one
+/// of the chains the collection is bridged to.
+fn charge_signing(config: &CollectionConfig, chain_id: u64) -> Result<(), String> {
+    let bridged = config
+        .evm_chain_ids
+        .as_ref()
+        .is_some_and(|chain_ids| chain_ids.contains(&chain_id));
+    let providers = config.evm_rpc_urls(chain_id).len() as u128;
+
+    if !bridged || providers == 0 {
+        return Err(format!("Chain {} is not bridged", chain_id));
+    }
+
+    charge_rpc_call(providers * outcall_cycles("eth_getBlockByNumber"))
+        .map_err(|retry_after| format!("Rate limited, retry in {} seconds", retry_after))
+}
+
+/// `Init` to `FundReceived`, transfers the token from its owner to the custody account.
+fn take_custody(msg_id: u128, custody: &ICRCAccount, current_time: u64) -> TransferResult {
+    let error = |message: &str| TransferError::GenericError {
+        error_code: 0,
+        message: message.into(),
+    };
+
+    let status = get_mint_status(msg_id).ok_or(error("Unknown message id"))?;
+    let owner = status.owner.ok_or(error("Unknown owner"))?;
+
+    // requests still in `Init` never took the token
+    let held = state::pending_mints().into_iter().any(|(other, id)| {
+        other != msg_id
+            && id == status.id
+            && get_mint_status(other).is_some_and(|other| other.state != MintState::Init)
+    });
+    if held {
+        return Err(error("Token is already held by another pending mint"));
+    }
+
+    let config = get_icrc7_config();
+
+    let arg = TransferArg {
+        to: custody.clone(),
+        from_subaccount: owner.subaccount(),
+        token_id: status.id,
+        memo: None,
+        created_at_time: None,
+    };
+
+    let (token, block) = prepare_transfer(&config, current_time, &owner, &arg, &HashMap::new())?;
+    let block_index = commit_transfer(current_time, token, block);
+
+    set_mint_state(msg_id, MintState::FundReceived);
+
+    Ok(block_index)
+}
+
+/// `FundReceived` to `Signed`, everything before the await is already committed when signing
+/// fails, the entry then stays in `FundReceived` for a retry.
+async fn sign_mint(msg_id: u128) -> Result<SelfMintArgs, String> {
+    let status = get_mint_status(msg_id).ok_or("Unknown message id")?;
+
+    if status.state != MintState::FundReceived {
+        return Err("Mint is not awaiting a signature".into());
+    }
+
+    // entries of older versions did not record what was signed
+    let (chain_id, target_eth_wallet) = match (status.chain_id, status.to.clone()) {
+        (Some(chain_id), Some(to)) => (chain_id, to),
+        _ => return Err("Mint cannot be signed again".into()),
+    };
+
+    let config = get_icrc7_config();
+
+    // Generate tECDSA signature
+    let cknft_eth_address =
+        eth_address(&config.cknft_eth_address).ok_or("Invalid CkNFT contract address")?;
+    let target_eth_wallet_bytes = eth_address(&target_eth_wallet).ok_or("Invalid target wallet")?;
+
+    // the mint can only land in a block after the finalized one of before signing
+    let rpc = HttpsEvmRpc::https(
+        config.evm_rpc_urls(chain_id),
+        config.evm_rpc_consensus.clone().unwrap_or_default(),
+    );
+    let from_block = rpc
+        .finalized_block()
+        .await
+        .map_err(|e| format!("Failed to read the finalized block: {}", e))?
+        .number;
+
+    let payload_to_sign = bridge::self_mint_payload(
+        status.id,
+        &target_eth_wallet_bytes,
+        msg_id,
+        status.expiry,
+        chain_id,
+        &cknft_eth_address,
+    );
+
+    let hashed_payload = raw_keccak256(&payload_to_sign).to_vec();
+
+    let args = SignWithEcdsaArgument {
+        derivation_path: vec![],
+        message_hash: hashed_payload.clone(),
+        key_id: EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: config.ecdsa_key_name,
+        },
+    };
+
+    let signature: Vec<u8> = {
+        let (res,): (SignWithEcdsaResponse,) = sign_with_ecdsa(args)
+            .await
+            .map_err(|e| format!("Failed to sign with ecdsa: {}", e.1))?;
+        res.signature
+    };
+
+    // the entry may have been expired while signing
+    let status = get_mint_status(msg_id)
+        .filter(|status| status.state == MintState::FundReceived)
+        .ok_or("Mint is not awaiting a signature")?;
+
+    let sec1_public_key = PUBLIC_KEY.with(|pk| pk.borrow().get().clone());
+
+    let public_key =
+        VerifyingKey::from_sec1_bytes(&sec1_public_key).map_err(|_| "Invalid public key")?;
+
+    let recid = RecoveryId::trial_recovery_from_prehash(
+        &public_key,
+        &hashed_payload,
+        &Signature::from_slice(signature.as_slice()).map_err(|_| "Invalid signature")?,
+    )
+    .map_err(|_| "Signature does not match the public key")?;
+
+    let v = recid.is_y_odd() as u8 + 27;
+
+    SIGNATURE_MAP.with(|sm| {
+        let mut sm = sm.borrow_mut();
+        sm.insert(msg_id, EcdsaSignature::from_signature_v(&signature, v));
+    });
+
+    let status = MintStatus {
+        state: MintState::Signed,
+        from_block: Some(from_block),
+        ..status
+    };
+    update_status(msg_id, status.clone());
+
+    signed_mint(msg_id, &status).ok_or("Signature not found".into())
+}
+
+fn signed_mint(msg_id: u128, status: &MintStatus) -> Option<SelfMintArgs> {
+    let signature = SIGNATURE_MAP.with(|sm| sm.borrow().get(&msg_id))?;
+
+    Some(SelfMintArgs {
+        id: status.id,
+        to: status.to.clone()?,
+        msgid: msg_id,
+        expiry: status.expiry,
+        signature: signature.to_string(),
+    })
+}
+
+fn eth_address(address: &str) -> Option<[u8; 20]> {
+    hex_string_with_0x_to_vec(address).ok()?.try_into().ok()
+}
+
+#[update(guard = "caller_is_bridge_operator")]
+pub async fn update_cknft_state() -> Vec<u8> {
+    let config = get_icrc7_config();
+
+    let args = EcdsaPublicKeyArgument {
+        canister_id: None,
+        derivation_path: vec![],
+        key_id: EcdsaKeyId {
+            curve: EcdsaCurve::Secp256k1,
+            name: config.ecdsa_key_name,
+        },
+    };
+
+    // Update tecdsa signer key and calculate signer ETH address
+    let (res,): (EcdsaPublicKeyResponse,) = ecdsa_public_key(args)
+        .await
+        .unwrap_or_else(|_| ic_cdk::trap("Failed to get ecdsa public key"));
+
+    PUBLIC_KEY.with(|pk| {
+        let mut pk = pk.borrow_mut();
+        pk.set(res.public_key.clone()).unwrap();
+    });
+
+    res.public_key
+}
+
+ic_cdk::export_candid!();
+
+#[cfg(test)]
+fn test_token(id: u128, owner: Principal) -> Token {
+    Token {
+        id,
+        owner: ICRCAccount::from(owner),
+        name: format!("Token {}", id),
+        image: None,
+        description: None,
+        approvals: vec![],
+        asset_id: None,
+    }
+}
+
+#[test]
+fn test_atomic_transfer_batch_rolls_back() {
+    let alice = Principal::from_slice(&[1; 10]);
+    let bob = ICRCAccount::from(Principal::from_slice(&[2; 10]));
+    let carol = Principal::from_slice(&[3; 10]);
+
+    insert_token(test_token(1, alice));
+    insert_token(test_token(2, carol));
+
+    let transfer = |token_id| TransferArg {
+        from_subaccount: None,
+        to: bob.clone(),
+        token_id,
+        memo: None,
+        created_at_time: None,
+    };
+    let args = [transfer(1), transfer(2)];
+    let owner_of = |id| TOKENS.with(|tokens| tokens.borrow().get(&id).unwrap().owner);
+
+    let config = CollectionConfig {
+        atomic_batch_transfers: Some(true),
+        ..Default::default()
+    };
+    let results = transfer_batch(&config, 0, alice, &args);
+    assert!(matches!(
+        results[0],
+        Some(Err(TransferError::GenericBatchError { error_code: 1, .. }))
+    ));
+    assert!(matches!(results[1], Some(Err(TransferError::Unauthorized))));
+    assert_eq!(owner_of(1), ICRCAccount::from(alice));
+    assert_eq!(state::get_log_tip().length, 0);
+
+    let results = transfer_batch(&CollectionConfig::default(), 0, alice, &args);
+    assert!(matches!(results[0], Some(Ok(0))));
+    assert!(matches!(results[1], Some(Err(TransferError::Unauthorized))));
+    assert_eq!(owner_of(1), bob);
+}
+
+#[test]
+fn test_burn_lowers_supply_and_records_burned_id() {
+    let alice = Principal::from_slice(&[1; 10]);
+    let bob = Principal::from_slice(&[2; 10]);
+
+    insert_token(test_token(1, alice));
+    increment_total_supply();
+
+    let config = CollectionConfig {
+        burn_account: Some(ICRCAccount::from(Principal::from_slice(&[9; 10]))),
+        ..Default::default()
+    };
+    let burn = BurnArg {
+        from_subaccount: None,
+        token_id: 1,
+        memo: None,
+        created_at_time: None,
+    };
+
+    assert!(matches!(
+        burn_token(&config, 0, bob, &burn),
+        Err(BurnError::Unauthorized)
+    ));
+    assert_eq!(get_total_supply(), 1);
+
+    assert!(matches!(burn_token(&config, 0, alice, &burn), Ok(0)));
+    assert_eq!(get_total_supply(), 0);
+    assert!(is_burned_id(1));
+    assert!(TOKENS.with(|tokens| !tokens.borrow().contains_key(&1)));
+
+    assert!(matches!(
+        burn_token(&config, 0, alice, &burn),
+        Err(BurnError::NonExistingTokenId)
+    ));
+}
+
+#[cfg(test)]
+fn test_mint_args(id: Option<u128>, to: Principal) -> MintArgs {
+    MintArgs {
+        id,
+        name: "Token".into(),
+        description: None,
+        image: None,
+        asset_id: None,
+        to: ICRCAccount::from(to),
+    }
+}
+
+#[test]
+fn test_mint_supply_cap_and_next_token_id() {
+    let minter = Principal::from_slice(&[1; 10]);
+    let alice = Principal::from_slice(&[2; 10]);
+
+    let mint = |config: &CollectionConfig, id| {
+        let (token, block) =
+            mint_token(config, minter, test_mint_args(id, alice), &BTreeSet::new())?;
+        commit_mint(0, minter, token.clone(), block);
+
+        Ok::<u128, MintError>(token.id)
+    };
+
+    let capped = CollectionConfig {
+        supply_cap: Some(2),
+        burn_account: Some(ICRCAccount::from(Principal::from_slice(&[9; 10]))),
+        ..Default::default()
+    };
+    assert!(matches!(mint(&capped, None), Ok(0)));
+    assert!(matches!(mint(&capped, None), Ok(1)));
+    assert!(matches!(
+        mint(&capped, None),
+        Err(MintError::SupplyCapReached)
+    ));
+    assert!(matches!(
+        mint(&capped, Some(1)),
+        Err(MintError::SupplyCapReached)
+    ));
+
+    let burn = BurnArg {
+        from_subaccount: None,
+        token_id: 1,
+        memo: None,
+        created_at_time: None,
+    };
+    assert!(burn_token(&capped, 0, alice, &burn).is_ok());
+
+    // burned ids are neither reused nor minted again
+    assert!(matches!(
+        mint(&capped, Some(1)),
+        Err(MintError::TokenIdBurned)
+    ));
+    assert!(matches!(mint(&capped, None), Ok(2)));
+    assert!(matches!(
+        mint(&capped, Some(0)),
+        Err(MintError::SupplyCapReached)
+    ));
+    assert_eq!(get_total_supply(), 2);
+}
+
+#[test]
+fn test_atomic_mint_batch_rolls_back() {
+    let minter = Principal::from_slice(&[1; 10]);
+    let alice = Principal::from_slice(&[2; 10]);
+
+    insert_token(test_token(5, alice));
+    increment_total_supply();
+
+    let args = || {
+        vec![
+            test_mint_args(None, alice),
+            test_mint_args(Some(5), alice),
+            test_mint_args(None, alice),
+        ]
+    };
+
+    let config = CollectionConfig {
+        atomic_batch_mints: Some(true),
+        ..Default::default()
+    };
+    let results = mint_batch(&config, 0, minter, args());
+    assert!(matches!(
+        results[0],
+        Some(Err(MintError::GenericBatchError { error_code: 1, .. }))
+    ));
+    assert!(matches!(results[1], Some(Err(MintError::TokenIdExists))));
+    assert_eq!(get_total_supply(), 1);
+    assert_eq!(state::get_log_tip().length, 0);
+
+    // items commit one by one, automatic ids continue past the highest one
+    let results = mint_batch(&CollectionConfig::default(), 0, minter, args());
+    assert!(matches!(results[0], Some(Ok(0))));
+    assert!(matches!(results[1], Some(Err(MintError::TokenIdExists))));
+    assert!(matches!(results[2], Some(Ok(1))));
+    assert!(
+        TOKENS.with(|tokens| tokens.borrow().contains_key(&6) && tokens.borrow().contains_key(&7))
+    );
+    assert_eq!(get_total_supply(), 3);
+}
+
+#[test]
+fn test_retried_transfer_and_burn_are_duplicates() {
+    let alice = Principal::from_slice(&[1; 10]);
+    let bob = Principal::from_slice(&[2; 10]);
+
+    insert_token(test_token(1, alice));
+    increment_total_supply();
+
+    let config = CollectionConfig {
+        tx_window: 100,
+        ..Default::default()
+    };
+    let transfer = TransferArg {
+        from_subaccount: None,
+        to: ICRCAccount::from(bob),
+        token_id: 1,
+        memo: None,
+        created_at_time: Some(1_000),
+    };
+
+    assert!(matches!(
+        transfer_token(&config, 1_000, alice, &transfer),
+        Ok(0)
+    ));
+    // bob owns the token by now, the retry is still recognized
+    assert!(matches!(
+        transfer_token(&config, 1_050, alice, &transfer),
+        Err(TransferError::Duplicate { duplicate_of: 0 })
+    ));
+
+    let burn = BurnArg {
+        from_subaccount: None,
+        token_id: 1,
+        memo: None,
+        created_at_time: Some(1_000),
+    };
+    assert!(matches!(burn_token(&config, 1_000, bob, &burn), Ok(1)));
+    assert!(matches!(
+        burn_token(&config, 1_050, bob, &burn),
+        Err(BurnError::Duplicate { duplicate_of: 1 })
+    ));
+}
+
+#[test]
+fn test_mint_only_references_own_assets() {
+    let minter = Principal::from_slice(&[1; 10]);
+    let other = Principal::from_slice(&[3; 10]);
+
+    let theirs = create_asset(other, DEFAULT_CONTENT_TYPE.into(), b"theirs".to_vec());
+    let ours = create_asset(minter, DEFAULT_CONTENT_TYPE.into(), b"ours".to_vec());
+
+    let mint = |asset_id| {
+        let arg = MintArgs {
+            asset_id: Some(asset_id),
+            ..test_mint_args(None, minter)
+        };
+        mint_token(&CollectionConfig::default(), minter, arg, &BTreeSet::new())
+    };
+
+    assert!(matches!(mint(theirs), Err(MintError::AssetNotFound)));
+    assert!(mint(ours).is_ok_and(|(token, _)| token.asset_id == Some(ours)));
+}
+
+#[cfg(test)]
+fn test_mint_status(id: u128, owner: Principal) -> MintStatus {
+    MintStatus {
+        id,
+        amount: 1,
+        expiry: 1_000,
+        state: MintState::Init,
+        owner: Some(ICRCAccount::from(owner)),
+        chain_id: Some(1),
+        from_block: None,
+        to: None,
+    }
+}
+
+#[test]
+fn test_mint_statuses_are_paginated_newest_first() {
+    let alice = Principal::from_slice(&[1; 10]);
+    let alice_subaccount = Subaccount::from(alice);
+
+    let mut msg_ids = vec![];
+    for nonce in 1..=5 {
+        let msg_id = calc_msgid(&alice_subaccount, Nonce::new(Some(nonce)));
+        update_status(msg_id, test_mint_status(nonce as u128, alice));
+        msg_ids.push(msg_id);
+    }
+    NONCE_MAP.with(|nonce_map| {
+        nonce_map
+            .borrow_mut()
+            .insert(alice_subaccount, Nonce::new(Some(5)))
+    });
+    msg_ids.reverse();
+
+    let page = |prev, take| {
+        mint_statuses_of(alice, prev, take)
+            .into_iter()
+            .map(|(msg_id, _)| msg_id)
+            .collect::<Vec<_>>()
+    };
+
+    assert_eq!(page(None, 2), msg_ids[..2]);
+    assert_eq!(page(Some(msg_ids[1]), 2), msg_ids[2..4]);
+    assert_eq!(page(Some(msg_ids[3]), 2), msg_ids[4..]);
+    assert!(page(Some(msg_ids[4]), 2).is_empty());
+    assert!(page(Some(0), 2).is_empty());
+    assert!(mint_statuses_of(Principal::from_slice(&[2; 10]), None, 2).is_empty());
+}
+
+#[test]
+fn test_expired_mint_refunds_custody_token() {
+    use crate::evm_rpc::{block_on, BlockHeader, MockEvmRpc};
+
+    let alice = Principal::from_slice(&[1; 10]);
+    let custody = ICRCAccount::from(Principal::from_slice(&[9; 10]));
+
+    insert_token(test_token(1, custody.owner()));
+    let owner_of = |id| TOKENS.with(|tokens| tokens.borrow().get(&id).unwrap().owner);
+
+    let status = MintStatus {
+        state: MintState::Signed,
+        from_block: Some(1),
+        to: Some("0x0000000000000000000000000000000000000001".into()),
+        ..test_mint_status(1, alice)
+    };
+    update_status(7, status.clone());
+
+    // past expiry on EVM without a `SelfMint` log
+    let rpc = MockEvmRpc {
+        head: BlockHeader {
+            number: 10,
+            timestamp: 2_000,
+        },
+        ..Default::default()
+    };
+    let checked = block_on(bridge::check_mint_status(&rpc, "0x", 7, &status, 2_000)).unwrap();
+    assert_eq!(checked.state, MintState::Expired);
+
+    // a newer request holds the token in custody
+    update_status(
+        8,
+        MintStatus {
+            state: MintState::FundReceived,
+            ..test_mint_status(1, alice)
+        },
+    );
+    let (expired, refund) = expire_mint(7, status.clone(), &custody, 0);
+    assert!(matches!(refund, Err(TransferError::GenericError { .. })));
+    assert_eq!(expired.state, MintState::Refunding);
+    assert_eq!(owner_of(1), custody);
+
+    update_status(
+        8,
+        MintStatus {
+            state: MintState::Expired,
+            ..test_mint_status(1, alice)
+        },
+    );
+    let (expired, refund) = expire_mint(7, expired, &custody, 0);
+    assert!(refund.is_ok());
+    assert_eq!(expired.state, MintState::Expired);
+    assert!(get_mint_status(7).is_some_and(|status| status.state == MintState::Expired));
+    assert_eq!(owner_of(1), ICRCAccount::from(alice));
+    assert!(state::pending_mints().is_empty());
+}
+
+#[test]
+fn test_bridge_lock_is_released_on_drop() {
+    let lock = BridgeLock::acquire(1).unwrap();
+    assert!(BridgeLock::is_locked(1));
+    assert!(BridgeLock::acquire(1).is_err());
+    assert!(BridgeLock::acquire(2).is_ok());
+    assert!(!BridgeLock::is_locked(2));
+
+    drop(lock);
+    assert!(!BridgeLock::is_locked(1));
+    assert!(BridgeLock::acquire(1).is_ok());
+}
+
+#[test]
+fn test_custody_and_retry_of_a_mint() {
+    let alice = Principal::from_slice(&[1; 10]);
+    let custody = ICRCAccount::from(Principal::from_slice(&[9; 10]));
+
+    insert_token(test_token(1, alice));
+
+    update_status(7, test_mint_status(1, alice));
+    assert!(matches!(
+        resumable_mint(7, &test_mint_status(1, alice), 0),
+        Ok(None)
+    ));
+    assert!(take_custody(7, &custody, 0).is_ok());
+    assert!(get_mint_status(7).is_some_and(|status| status.state == MintState::FundReceived));
+    assert_eq!(
+        TOKENS.with(|tokens| tokens.borrow().get(&1).unwrap().owner),
+        custody
+    );
+
+    // the token is already held by another pending mint
+    update_status(8, test_mint_status(1, alice));
+    assert!(matches!(
+        take_custody(8, &custody, 0),
+        Err(TransferError::GenericError { message, .. })
+            if message == "Token is already held by another pending mint"
+    ));
+    assert!(get_mint_status(8).is_some_and(|status| status.state == MintState::Init));
+
+    let signed = MintStatus {
+        state: MintState::Signed,
+        to: Some("0x0000000000000000000000000000000000000001".into()),
+        ..test_mint_status(1, alice)
+    };
+    assert!(resumable_mint(7, &signed, 0).is_err_and(|err| err == "Signature not found"));
+
+    let signature = EcdsaSignature::from_signature_v(&[3; 64], 27);
+    SIGNATURE_MAP.with(|sm| sm.borrow_mut().insert(7, signature.clone()));
+    let args = resumable_mint(7, &signed, 0).unwrap().unwrap();
+    assert_eq!(args.msgid, 7);
+    assert_eq!(args.signature, signature.to_string());
+
+    let confirmed = MintStatus {
+        state: MintState::Confirmed,
+        ..signed
+    };
+    assert!(resumable_mint(7, &confirmed, 0).is_err());
+    assert!(resumable_mint(8, &test_mint_status(1, alice), 1_000).is_err());
+}
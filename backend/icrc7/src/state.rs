@@ -1,7 +1,10 @@
 use crate::{
     crypto::EcdsaSignature,
-    types::{ApprovalError, TransferError},
-    types::{CollectionMetadata, Memo, MintStatus},
+    types::{ApprovalError, ApprovalInfo, TimeWindowError, TransferError},
+    types::{
+        ArchiveOptions, CollectionMetadata, ConsensusStrategy, EvmRpcProvider, Memo, MintStatus,
+        Role, RoleAssignment, Value,
+    },
 };
 use b3_utils::{
     ledger::{ICRC1MetadataValue, ICRCAccount},
@@ -12,21 +15,52 @@ use b3_utils::{
     nonce::Nonce,
     Subaccount,
 };
-use candid::{CandidType, Decode, Encode, Nat};
+use candid::{CandidType, Decode, Encode, Nat, Principal};
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
 use std::cell::RefCell;
 
+pub const DEFAULT_TAKE_VALUE: u128 = 100;
+pub const MAX_TAKE_VALUE: u128 = 1000;
+pub const MAX_QUERY_BATCH_SIZE: u128 = 1000;
+pub const MAX_UPDATE_BATCH_SIZE: u128 = 100;
+/// Confirmations are counted up to the finalized block, so a finalized burn is enough.
+pub const DEFAULT_EVM_MIN_CONFIRMATIONS: u64 = 1;
+/// Version of the stable data written by this code, new installs start from it.
+pub const LATEST_STATE_VERSION: u64 = 1;
+
 thread_local! {
     pub static CONFIG: RefCell<DefaultStableCell<CollectionConfig>> = init_stable_mem_refcell("config", 1).unwrap();
     pub static TOKENS: RefCell<DefaultStableBTreeMap<u128, Token>> = init_stable_mem_refcell("tokens", 2).unwrap();
+    // pre ICRC-3 transfer log, only read once to migrate it into `BLOCK_LOG`, id 4 was its counter
     pub static TRANSFER_LOG: RefCell<DefaultStableVec<TransferLog>> = init_stable_mem_refcell("transfer_log", 3).unwrap();
-    pub static TRANSACTION_ID: RefCell<DefaultStableCell<u128>> = init_stable_mem_refcell("transaction_id", 4).unwrap();
     pub static TOTAL_SUPPLY: RefCell<DefaultStableCell<u128>> = init_stable_mem_refcell("total_supply", 5).unwrap();
     pub static NONCE_MAP: RefCell<DefaultStableBTreeMap<Subaccount, Nonce>> = init_stable_mem_refcell("nonce_map", 6).unwrap();
     pub static STATUS_MAP: RefCell<DefaultStableBTreeMap<u128, MintStatus>> = init_stable_mem_refcell("status_map", 7).unwrap();
     pub static SIGNATURE_MAP: RefCell<DefaultStableBTreeMap<u128, EcdsaSignature>> = init_stable_mem_refcell("signature_map", 8).unwrap();
     pub static PUBLIC_KEY: RefCell<DefaultStableCell<Vec<u8>>> = init_stable_mem_refcell("cknft_state", 9).unwrap();
+    pub static COLLECTION_APPROVALS: RefCell<DefaultStableBTreeMap<OperatorKey, Approval>> = init_stable_mem_refcell("collection_approvals", 10).unwrap();
+    pub static OWNER_INDEX: RefCell<DefaultStableBTreeMap<OwnerTokenKey, ()>> = init_stable_mem_refcell("owner_index", 11).unwrap();
+    pub static BURNED_IDS: RefCell<DefaultStableBTreeMap<u128, ()>> = init_stable_mem_refcell("burned_ids", 12).unwrap();
+    pub static BLOCK_LOG: RefCell<DefaultStableBTreeMap<u64, Value>> = init_stable_mem_refcell("block_log", 13).unwrap();
+    pub static LOG_TIP: RefCell<DefaultStableCell<LogTip>> = init_stable_mem_refcell("log_tip", 14).unwrap();
+    pub static ARCHIVES: RefCell<DefaultStableBTreeMap<u64, Archive>> = init_stable_mem_refcell("archives", 15).unwrap();
+    pub static ARCHIVE_WASM: RefCell<DefaultStableCell<Vec<u8>>> = init_stable_mem_refcell("archive_wasm", 16).unwrap();
+    pub static DEDUP_INDEX: RefCell<DefaultStableBTreeMap<DedupKey, u64>> = init_stable_mem_refcell("dedup_index", 17).unwrap();
+    pub static ROLES: RefCell<DefaultStableBTreeMap<RoleKey, ()>> = init_stable_mem_refcell("roles", 18).unwrap();
+    pub static ASSETS: RefCell<DefaultStableBTreeMap<u64, Asset>> = init_stable_mem_refcell("assets", 19).unwrap();
+    pub static ASSET_CHUNKS: RefCell<DefaultStableBTreeMap<AssetChunkKey, Vec<u8>>> = init_stable_mem_refcell("asset_chunks", 20).unwrap();
+    pub static NEXT_ASSET_ID: RefCell<DefaultStableCell<u64>> = init_stable_mem_refcell("next_asset_id", 21).unwrap();
+    pub static PROCESSED_BURNS: RefCell<DefaultStableBTreeMap<BurnEventKey, u128>> = init_stable_mem_refcell("processed_burns", 22).unwrap();
+    /// Principal bytes of an archive created but not installed yet, empty when there is none.
+    pub static PENDING_ARCHIVE: RefCell<DefaultStableCell<Vec<u8>>> = init_stable_mem_refcell("pending_archive", 23).unwrap();
+    pub static PAUSED: RefCell<DefaultStableCell<bool>> = init_stable_mem_refcell("paused", 24).unwrap();
+    /// Uploads not committed yet, by asset id, with the time they began.
+    pub static PENDING_UPLOADS: RefCell<DefaultStableBTreeMap<u64, u64>> = init_stable_mem_refcell("pending_uploads", 25).unwrap();
+    /// Message ids of the pending entries of `STATUS_MAP`, with their token id.
+    pub static PENDING_MINTS: RefCell<DefaultStableBTreeMap<u128, u128>> = init_stable_mem_refcell("pending_mints", 26).unwrap();
+    /// Raised by each migration run on upgrade so that it runs once.
+    pub static STATE_VERSION: RefCell<DefaultStableCell<u64>> = init_stable_mem_refcell("state_version", 27).unwrap();
 }
 
 pub fn get_icrc7_config() -> CollectionConfig {
@@ -50,16 +84,30 @@ pub struct CollectionConfig {
     pub max_take_value: Option<u128>,
     pub max_memo_size: Option<u128>,
     pub atomic_batch_transfers: Option<bool>,
+    /// A mint batch either lands completely or leaves the collection untouched.
+    pub atomic_batch_mints: Option<bool>,
     pub tx_window: u64,
     pub permitted_drift: u64,
     pub cknft_eth_address: String,
     pub ecdsa_key_name: String,
+    /// Recipient recorded on burn logs, when set burned ids are reserved and never minted again.
+    pub burn_account: Option<ICRCAccount>,
+    /// Moves old blocks out to archive canisters, the whole log stays local when unset.
+    pub archive_options: Option<ArchiveOptions>,
+    /// Minter granted on install, later minters are rotated with `set_minting_authorities`.
+    pub minting_authority: Option<Principal>,
+    /// Chains with a deployed CkNFT contract, their `/evm/cknft/<chain_id>/<id>` metadata is certified.
+    pub evm_chain_ids: Option<Vec<u64>>,
+    pub evm_rpc_providers: Option<Vec<EvmRpcProvider>>,
+    pub evm_rpc_consensus: Option<ConsensusStrategy>,
+    /// Finalized blocks from a burn on before its token is released, `DEFAULT_EVM_MIN_CONFIRMATIONS` when unset.
+    pub evm_min_confirmations: Option<u64>,
 }
 
 impl Storable for CollectionConfig {
     const BOUND: Bound = Bound::Unbounded;
 
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Owned(Encode!(&self).unwrap())
     }
 
@@ -86,7 +134,21 @@ impl CollectionConfig {
     }
 
     pub fn supply_cap(&self) -> Option<u128> {
-        self.supply_cap.clone()
+        self.supply_cap
+    }
+
+    pub fn evm_rpc_urls(&self, chain_id: u64) -> Vec<String> {
+        self.evm_rpc_providers
+            .iter()
+            .flatten()
+            .filter(|provider| provider.chain_id == chain_id)
+            .map(|provider| provider.url.clone())
+            .collect()
+    }
+
+    pub fn evm_min_confirmations(&self) -> u64 {
+        self.evm_min_confirmations
+            .unwrap_or(DEFAULT_EVM_MIN_CONFIRMATIONS)
     }
 
     pub fn metadata(&self) -> CollectionMetadata {
@@ -109,14 +171,16 @@ impl CollectionConfig {
     }
 }
 
-#[derive(CandidType, Serialize, Deserialize)]
+#[derive(CandidType, Serialize, Deserialize, Clone)]
 pub struct Token {
     pub id: u128,
     pub owner: ICRCAccount,
     pub name: String,
+    /// Inline image of tokens minted before the asset store, moved into it on upgrade.
     pub image: Option<Vec<u8>>,
     pub description: Option<String>,
     pub approvals: Vec<Approval>,
+    pub asset_id: Option<u64>,
 }
 
 impl Token {
@@ -127,12 +191,11 @@ impl Token {
             ICRC1MetadataValue::Nat(Nat::from(self.id)),
         ));
         metadata.push(("Name".into(), ICRC1MetadataValue::Text(self.name.clone())));
-        if self.image.is_some() {
-            let buf = ByteBuf::from(self.image.as_ref().unwrap().clone());
-            metadata.push(("Image".into(), ICRC1MetadataValue::Blob(buf)))
+        if let Some(asset_id) = self.asset_id {
+            metadata.push(("Asset".into(), ICRC1MetadataValue::Nat(Nat::from(asset_id))))
         }
-        if self.description.is_some() {
-            let value = self.description.as_ref().unwrap().clone();
+        if let Some(description) = &self.description {
+            let value = description.clone();
             metadata.push(("Description".into(), ICRC1MetadataValue::Text(value)))
         }
         metadata
@@ -143,16 +206,10 @@ impl Token {
     }
 
     pub fn approval_check(&self, current_time: u64, account: &ICRCAccount) -> bool {
-        for approval in self.approvals.iter() {
-            if approval.account == *account {
-                if approval.expires_at.is_none() {
-                    return true;
-                } else if approval.expires_at >= Some(current_time) {
-                    return true;
-                }
-            }
-        }
-        false
+        self.approvals
+            .iter()
+            .any(|approval| approval.account == *account && approval.is_active(current_time))
+            || collection_approval_check(current_time, &self.owner, account)
     }
 
     pub fn approve(
@@ -161,18 +218,33 @@ impl Token {
         approval: Approval,
     ) -> Result<(), ApprovalError> {
         if self.owner == approval.account {
-            ic_cdk::trap("Self Approve")
+            return Err(ApprovalError::InvalidSpender);
         }
         if *caller != self.owner {
-            return Err(ApprovalError::Unauthorized {
+            Err(ApprovalError::Unauthorized {
                 tokens_ids: vec![self.id],
-            });
+            })
         } else {
+            // a new approval for the same spender replaces the previous one
+            self.approvals.retain(|a| a.account != approval.account);
             self.approvals.push(approval);
             Ok(())
         }
     }
 
+    /// Removes the approval of `spender`, or every approval when `None`.
+    /// Returns `false` if nothing was removed.
+    pub fn revoke(&mut self, spender: Option<&ICRCAccount>) -> bool {
+        let before = self.approvals.len();
+
+        match spender {
+            Some(spender) => self.approvals.retain(|a| a.account != *spender),
+            None => self.approvals.clear(),
+        }
+
+        self.approvals.len() != before
+    }
+
     pub fn transfer(
         &mut self,
         permitted_time: u64,
@@ -183,11 +255,11 @@ impl Token {
             ic_cdk::trap("Self Transfer")
         }
         if self.owner != *caller && !self.approval_check(permitted_time, caller) {
-            return Err(TransferError::Unauthorized);
+            Err(TransferError::Unauthorized)
         } else {
             self.owner = to;
             self.approvals.clear();
-            return Ok(());
+            Ok(())
         }
     }
 }
@@ -195,7 +267,26 @@ impl Token {
 impl Storable for Token {
     const BOUND: Bound = Bound::Unbounded;
 
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+/// Secondary index entry, one per token, ordered by owner and then token id.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OwnerTokenKey {
+    pub owner: ICRCAccount,
+    pub id: u128,
+}
+
+impl Storable for OwnerTokenKey {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Owned(Encode!(&self).unwrap())
     }
 
@@ -204,6 +295,206 @@ impl Storable for Token {
     }
 }
 
+/// Writes `token` to `TOKENS` and moves its `OWNER_INDEX` entry when the owner changed.
+pub fn insert_token(token: Token) {
+    let new_key = OwnerTokenKey {
+        owner: token.owner(),
+        id: token.id,
+    };
+
+    let previous = TOKENS.with(|tokens| tokens.borrow_mut().insert(token.id, token));
+
+    OWNER_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+
+        if let Some(previous) = previous {
+            if previous.owner == new_key.owner {
+                return;
+            }
+
+            index.remove(&OwnerTokenKey {
+                owner: previous.owner,
+                id: new_key.id,
+            });
+        }
+
+        index.insert(new_key, ());
+    });
+}
+
+/// Removes `id` from `TOKENS` together with its `OWNER_INDEX` entry.
+pub fn remove_token(id: u128) -> Option<Token> {
+    let token = TOKENS.with(|tokens| tokens.borrow_mut().remove(&id))?;
+
+    OWNER_INDEX.with(|index| {
+        index.borrow_mut().remove(&OwnerTokenKey {
+            owner: token.owner(),
+            id,
+        })
+    });
+
+    Some(token)
+}
+
+/// Token ids of the whole collection in ascending order, starting after `prev`.
+pub fn token_ids(prev: Option<u128>, take: usize) -> Vec<u128> {
+    let start = match prev {
+        None => 0,
+        Some(prev) => match prev.checked_add(1) {
+            None => return vec![],
+            Some(start) => start,
+        },
+    };
+
+    TOKENS.with(|tokens| {
+        tokens
+            .borrow()
+            .range(start..)
+            .map(|(id, _)| id)
+            .take(take)
+            .collect()
+    })
+}
+
+/// Token ids of `owner` in ascending order, starting after `prev`.
+pub fn owner_token_ids(owner: &ICRCAccount, prev: Option<u128>, take: usize) -> Vec<u128> {
+    let start = OwnerTokenKey {
+        owner: owner.clone(),
+        id: prev.unwrap_or_default(),
+    };
+
+    OWNER_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.owner == *owner)
+            .map(|(key, _)| key.id)
+            .filter(|id| Some(*id) != prev)
+            .take(take)
+            .collect()
+    })
+}
+
+pub fn owner_token_count(owner: &ICRCAccount) -> u128 {
+    let start = OwnerTokenKey {
+        owner: owner.clone(),
+        id: 0,
+    };
+
+    OWNER_INDEX.with(|index| {
+        index
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.owner == *owner)
+            .count() as u128
+    })
+}
+
+/// Fills `OWNER_INDEX` from `TOKENS`, a no-op once the index is populated.
+pub fn rebuild_owner_index() {
+    let is_empty = OWNER_INDEX.with(|index| index.borrow().is_empty());
+    if !is_empty {
+        return;
+    }
+
+    TOKENS.with(|tokens| {
+        OWNER_INDEX.with(|index| {
+            let mut index = index.borrow_mut();
+
+            for (id, token) in tokens.borrow().iter() {
+                index.insert(
+                    OwnerTokenKey {
+                        owner: token.owner,
+                        id,
+                    },
+                    (),
+                );
+            }
+        })
+    });
+}
+
+/// Adds or removes `msg_id` from `PENDING_MINTS`, called on every write of `STATUS_MAP`.
+pub fn index_pending_mint(msg_id: u128, status: &MintStatus) {
+    PENDING_MINTS.with(|pending| {
+        let mut pending = pending.borrow_mut();
+
+        if status.is_pending() {
+            pending.insert(msg_id, status.id);
+        } else {
+            pending.remove(&msg_id);
+        }
+    });
+}
+
+/// Message ids of the pending entries, with their token id.
+pub fn pending_mints() -> Vec<(u128, u128)> {
+    PENDING_MINTS.with(|pending| pending.borrow().iter().collect())
+}
+
+/// Fills `PENDING_MINTS` from `STATUS_MAP`, a no-op once the index is populated.
+pub fn rebuild_pending_mints() {
+    let is_empty = PENDING_MINTS.with(|pending| pending.borrow().is_empty());
+    if !is_empty {
+        return;
+    }
+
+    STATUS_MAP.with(|status_map| {
+        for (msg_id, status) in status_map.borrow().iter() {
+            index_pending_mint(msg_id, &status);
+        }
+    });
+}
+
+/// Uploaded asset, its bytes live in `ASSET_CHUNKS`. `sha256` is only set once committed.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Asset {
+    pub owner: Principal,
+    pub content_type: String,
+    pub length: u64,
+    pub chunk_count: u32,
+    pub sha256: Option<ByteBuf>,
+}
+
+impl Storable for Asset {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct AssetChunkKey {
+    pub asset_id: u64,
+    pub index: u32,
+}
+
+impl Storable for AssetChunkKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 12,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.asset_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.index.to_be_bytes());
+
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            asset_id: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            index: u32::from_be_bytes(bytes[8..].try_into().unwrap()),
+        }
+    }
+}
+
 pub fn id_validity_check(id: u128) {
     TOKENS.with(|tokens| match tokens.borrow().get(&id) {
         Some(_) => (),
@@ -229,7 +520,26 @@ impl Storable for TransferLog {
         is_fixed_size: false,
     };
 
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+/// Length of the block log and hash of its last block, the `phash` of the next one.
+#[derive(CandidType, Serialize, Deserialize, Clone, Default)]
+pub struct LogTip {
+    pub length: u64,
+    pub last_hash: Option<Vec<u8>>,
+}
+
+impl Storable for LogTip {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Owned(Encode!(&self).unwrap())
     }
 
@@ -238,44 +548,300 @@ impl Storable for TransferLog {
     }
 }
 
-pub fn increment_tx_id() -> u128 {
-    TRANSACTION_ID.with(|id| {
-        let mut id = id.borrow_mut();
-        let current_id = id.get().clone();
+/// Archive canister holding the blocks `start..start + length`.
+#[derive(CandidType, Serialize, Deserialize, Clone)]
+pub struct Archive {
+    pub canister_id: Principal,
+    pub start: u64,
+    pub length: u64,
+}
+
+impl Storable for Archive {
+    const BOUND: Bound = Bound::Unbounded;
 
-        id.set(current_id + 1).unwrap()
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+impl Storable for Value {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+/// Archives ordered by the index of their first block.
+pub fn get_archives() -> Vec<Archive> {
+    ARCHIVES.with(|archives| {
+        archives
+            .borrow()
+            .iter()
+            .map(|(_, archive)| archive)
+            .collect()
     })
 }
 
+pub fn state_version() -> u64 {
+    STATE_VERSION.with(|version| *version.borrow().get())
+}
+
+pub fn set_state_version(version: u64) {
+    STATE_VERSION.with(|cell| cell.borrow_mut().set(version).unwrap());
+}
+
+pub fn is_paused() -> bool {
+    PAUSED.with(|paused| *paused.borrow().get())
+}
+
+/// Returns whether the flag changed.
+pub fn set_paused(paused: bool) -> bool {
+    PAUSED.with(|cell| cell.borrow_mut().set(paused).unwrap() != paused)
+}
+
+pub fn pending_archive() -> Option<Principal> {
+    let bytes = PENDING_ARCHIVE.with(|pending| pending.borrow().get().clone());
+
+    (!bytes.is_empty()).then(|| Principal::from_slice(&bytes))
+}
+
+pub fn set_pending_archive(canister_id: Option<Principal>) {
+    let bytes = canister_id.map_or(vec![], |id| id.as_slice().to_vec());
+
+    PENDING_ARCHIVE.with(|pending| pending.borrow_mut().set(bytes).unwrap());
+}
+
+/// Index of the oldest block still held locally, everything before it is archived.
+pub fn first_local_block() -> u64 {
+    get_log_tip().length - BLOCK_LOG.with(|log| log.borrow().len())
+}
+
+pub fn get_log_tip() -> LogTip {
+    LOG_TIP.with(|tip| tip.borrow().get().clone())
+}
+
 pub fn increment_total_supply() {
     TOTAL_SUPPLY.with(|s| {
         let mut s = s.borrow_mut();
-        let current_supply = s.get().clone();
+        let current_supply = *s.get();
         s.set(current_supply + 1).unwrap();
     })
 }
 
+pub fn decrement_total_supply() {
+    TOTAL_SUPPLY.with(|s| {
+        let mut s = s.borrow_mut();
+        let current_supply = *s.get();
+        s.set(current_supply.saturating_sub(1)).unwrap();
+    })
+}
+
+pub fn is_burned_id(id: u128) -> bool {
+    BURNED_IDS.with(|ids| ids.borrow().contains_key(&id))
+}
+
+/// One past the highest id ever minted, burned ids included.
+pub fn next_token_id() -> u128 {
+    let last_token = TOKENS.with(|tokens| tokens.borrow().last_key_value().map(|(id, _)| id));
+    let last_burned = BURNED_IDS.with(|ids| ids.borrow().last_key_value().map(|(id, _)| id));
+
+    last_token
+        .max(last_burned)
+        .map_or(0, |id| id.saturating_add(1))
+}
+
 pub fn get_total_supply() -> u128 {
-    TOTAL_SUPPLY.with(|s| s.borrow().get().clone())
-}
-
-pub fn tx_deduplication_check(
-    permitted_past_time: u64,
-    created_at_time: u64,
-    memo: &Option<Memo>,
-    id: u128,
-    caller: &ICRCAccount,
-    to: &ICRCAccount,
-) -> Option<usize> {
-    TRANSFER_LOG.with(|log_ref| {
-        log_ref.borrow().iter().position(|log| {
-            log.at > permitted_past_time
-                && log.id == id
-                && log.at == created_at_time
-                && log.memo == *memo
-                && log.from == *caller
-                && log.to == *to
-        })
+    TOTAL_SUPPLY.with(|s| *s.borrow().get())
+}
+
+pub fn created_at_time_check(
+    config: &CollectionConfig,
+    current_time: u64,
+    created_at_time: Option<u64>,
+) -> Result<(), TimeWindowError> {
+    let created_at_time = match created_at_time {
+        None => return Ok(()),
+        Some(time) => time,
+    };
+
+    let (permitted_past_time, permitted_future_time) = permitted_time_window(config, current_time);
+
+    if created_at_time < permitted_past_time {
+        return Err(TimeWindowError::TooOld);
+    }
+    if created_at_time > permitted_future_time {
+        return Err(TimeWindowError::CreatedInFuture {
+            ledger_time: current_time,
+        });
+    }
+
+    Ok(())
+}
+
+pub fn memo_size_check(config: &CollectionConfig, memo: &Option<Memo>) -> Result<(), String> {
+    if let (Some(memo), Some(max_memo_size)) = (memo, config.max_memo_size) {
+        if memo.0.len() as u128 > max_memo_size {
+            return Err(format!("Memo exceeds max size of {} bytes", max_memo_size));
+        }
+    }
+
+    Ok(())
+}
+
+pub fn query_batch_size_check(config: &CollectionConfig, len: usize) -> Result<(), String> {
+    let max = config.max_query_batch_size.unwrap_or(MAX_QUERY_BATCH_SIZE);
+
+    if len as u128 > max {
+        return Err(format!(
+            "Batch size exceeds max_query_batch_size of {}",
+            max
+        ));
+    }
+
+    Ok(())
+}
+
+pub fn update_batch_size_check(config: &CollectionConfig, len: usize) -> Result<(), String> {
+    let max = config
+        .max_update_batch_size
+        .unwrap_or(MAX_UPDATE_BATCH_SIZE);
+
+    if len as u128 > max {
+        return Err(format!(
+            "Batch size exceeds max_update_batch_size of {}",
+            max
+        ));
+    }
+
+    Ok(())
+}
+
+/// Clamps a requested page size to the collection's `default_take_value` and `max_take_value`.
+pub fn take_value(config: &CollectionConfig, take: Option<u128>) -> usize {
+    let max_take = config.max_take_value.unwrap_or(MAX_TAKE_VALUE);
+    let take = take
+        .or(config.default_take_value)
+        .unwrap_or(DEFAULT_TAKE_VALUE)
+        .min(max_take);
+
+    take as usize
+}
+
+/// Returns the `(permitted_past_time, permitted_future_time)` bounds a `created_at_time` must fall into.
+pub fn permitted_time_window(config: &CollectionConfig, current_time: u64) -> (u64, u64) {
+    let permitted_past_time = current_time
+        .saturating_sub(config.tx_window)
+        .saturating_sub(config.permitted_drift);
+    let permitted_future_time = current_time + config.permitted_drift;
+
+    (permitted_past_time, permitted_future_time)
+}
+
+/// Dedup entry of a block carrying a `created_at_time`, ordered by that time so the index
+/// can be pruned from the front.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DedupKey {
+    pub created_at_time: u64,
+    /// Hash of the block type and the fields the caller supplied, its account, recipient,
+    /// token id and memo.
+    pub hash: [u8; 32],
+}
+
+impl Storable for DedupKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 40,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.created_at_time.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.hash);
+
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            created_at_time: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            hash: bytes[8..].try_into().unwrap(),
+        }
+    }
+}
+
+/// A `BurnToCkNFT` log, released at most once.
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct BurnEventKey {
+    pub chain_id: u64,
+    pub tx_hash: [u8; 32],
+    pub log_index: u64,
+}
+
+impl Storable for BurnEventKey {
+    const BOUND: Bound = Bound::Bounded {
+        max_size: 48,
+        is_fixed_size: true,
+    };
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        let mut bytes = self.chain_id.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.tx_hash);
+        bytes.extend_from_slice(&self.log_index.to_be_bytes());
+
+        std::borrow::Cow::Owned(bytes)
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Self {
+            chain_id: u64::from_be_bytes(bytes[..8].try_into().unwrap()),
+            tx_hash: bytes[8..40].try_into().unwrap(),
+            log_index: u64::from_be_bytes(bytes[40..].try_into().unwrap()),
+        }
+    }
+}
+
+/// Index of the transfer block that released the burned token.
+pub fn processed_burn(key: &BurnEventKey) -> Option<u128> {
+    PROCESSED_BURNS.with(|burns| burns.borrow().get(key))
+}
+
+pub fn insert_processed_burn(key: BurnEventKey, block_index: u128) {
+    PROCESSED_BURNS.with(|burns| burns.borrow_mut().insert(key, block_index));
+}
+
+/// Index of the block logged for the same transaction, if it is still inside the window.
+pub fn tx_deduplication_check(key: &DedupKey) -> Option<u64> {
+    DEDUP_INDEX.with(|index| index.borrow().get(key))
+}
+
+pub fn insert_dedup_entry(key: DedupKey, block_index: u64) {
+    DEDUP_INDEX.with(|index| index.borrow_mut().insert(key, block_index));
+}
+
+/// Drops the entries created before `before`, those are rejected as `TooOld` anyway.
+pub fn prune_dedup_index(before: u64) -> u64 {
+    DEDUP_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+
+        let expired: Vec<DedupKey> = index
+            .iter()
+            .take_while(|(key, _)| key.created_at_time < before)
+            .map(|(key, _)| key)
+            .collect();
+
+        for key in expired.iter() {
+            index.remove(key);
+        }
+
+        expired.len() as u64
     })
 }
 
@@ -283,15 +849,14 @@ pub fn tx_deduplication_check(
 pub struct Approval {
     pub expires_at: Option<u64>,
     pub account: ICRCAccount,
+    pub memo: Option<Memo>,
+    pub created_at_time: Option<u64>,
 }
 
 impl Storable for Approval {
-    const BOUND: Bound = Bound::Bounded {
-        is_fixed_size: false,
-        max_size: 100,
-    };
+    const BOUND: Bound = Bound::Unbounded;
 
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Owned(Encode!(&self).unwrap())
     }
 
@@ -305,17 +870,219 @@ impl Approval {
         Self {
             expires_at,
             account,
+            memo: None,
+            created_at_time: None,
+        }
+    }
+
+    pub fn with_memo(mut self, memo: Option<Memo>, created_at_time: u64) -> Self {
+        self.memo = memo;
+        self.created_at_time = Some(created_at_time);
+        self
+    }
+
+    pub fn is_active(&self, current_time: u64) -> bool {
+        match self.expires_at {
+            None => true,
+            Some(expires_at) => expires_at >= current_time,
+        }
+    }
+
+    pub fn info(&self, from_subaccount: Option<Subaccount>) -> ApprovalInfo {
+        ApprovalInfo {
+            spender: self.account.clone(),
+            from_subaccount,
+            expires_at: self.expires_at,
+            memo: self.memo.clone(),
+            created_at_time: self.created_at_time.unwrap_or_default(),
+        }
+    }
+}
+
+/// Key of a collection-wide approval, `operator` may transfer every token of `owner`.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct OperatorKey {
+    pub owner: ICRCAccount,
+    pub operator: ICRCAccount,
+}
+
+impl Storable for OperatorKey {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct RoleKey {
+    pub role: Role,
+    pub principal: Principal,
+}
+
+impl Storable for RoleKey {
+    const BOUND: Bound = Bound::Unbounded;
+
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
+        std::borrow::Cow::Owned(Encode!(&self).unwrap())
+    }
+
+    fn from_bytes(bytes: std::borrow::Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+}
+
+/// Holders of the `Owner` role pass every role check.
+pub fn has_role(principal: &Principal, role: Role) -> bool {
+    ROLES.with(|roles| {
+        let roles = roles.borrow();
+
+        [role, Role::Owner].iter().any(|role| {
+            roles.contains_key(&RoleKey {
+                role: *role,
+                principal: *principal,
+            })
+        })
+    })
+}
+
+pub fn grant_role(role: Role, principal: Principal) -> bool {
+    ROLES.with(|roles| {
+        roles
+            .borrow_mut()
+            .insert(RoleKey { role, principal }, ())
+            .is_none()
+    })
+}
+
+pub fn revoke_role(role: Role, principal: Principal) -> bool {
+    ROLES.with(|roles| {
+        roles
+            .borrow_mut()
+            .remove(&RoleKey { role, principal })
+            .is_some()
+    })
+}
+
+pub fn role_holders(role: Role) -> Vec<Principal> {
+    role_assignments(Some(role))
+        .into_iter()
+        .map(|assignment| assignment.principal)
+        .collect()
+}
+
+pub fn role_assignments(role: Option<Role>) -> Vec<RoleAssignment> {
+    ROLES.with(|roles| {
+        roles
+            .borrow()
+            .iter()
+            .filter(|(key, _)| role.is_none_or(|role| role == key.role))
+            .map(|(key, _)| RoleAssignment {
+                role: key.role,
+                principal: key.principal,
+            })
+            .collect()
+    })
+}
+
+pub fn collection_approval_check(
+    current_time: u64,
+    owner: &ICRCAccount,
+    operator: &ICRCAccount,
+) -> bool {
+    let key = OperatorKey {
+        owner: owner.clone(),
+        operator: operator.clone(),
+    };
+
+    COLLECTION_APPROVALS.with(|approvals| match approvals.borrow().get(&key) {
+        Some(approval) => approval.is_active(current_time),
+        None => false,
+    })
+}
+
+/// The smallest possible account, used as the lower bound when ranging over an owner's operators.
+fn min_account() -> ICRCAccount {
+    ICRCAccount::new(Principal::management_canister(), None)
+}
+
+pub fn collection_approvals(
+    owner: &ICRCAccount,
+    prev: Option<&ICRCAccount>,
+    take: usize,
+) -> Vec<Approval> {
+    let start = OperatorKey {
+        owner: owner.clone(),
+        operator: prev.cloned().unwrap_or_else(min_account),
+    };
+
+    COLLECTION_APPROVALS.with(|approvals| {
+        approvals
+            .borrow()
+            .range(start..)
+            .take_while(|(key, _)| key.owner == *owner)
+            .filter(|(key, _)| Some(&key.operator) != prev)
+            .map(|(_, approval)| approval)
+            .take(take)
+            .collect()
+    })
+}
+
+/// Removes the approval of `operator`, or every operator of `owner` when `None`.
+/// Returns `false` if nothing was removed.
+pub fn revoke_collection_approvals(owner: &ICRCAccount, operator: Option<&ICRCAccount>) -> bool {
+    let keys: Vec<OperatorKey> = match operator {
+        Some(operator) => vec![OperatorKey {
+            owner: owner.clone(),
+            operator: operator.clone(),
+        }],
+        None => collection_approvals(owner, None, usize::MAX)
+            .into_iter()
+            .map(|approval| OperatorKey {
+                owner: owner.clone(),
+                operator: approval.account,
+            })
+            .collect(),
+    };
+
+    COLLECTION_APPROVALS.with(|approvals| {
+        let mut approvals = approvals.borrow_mut();
+
+        let mut removed = false;
+        for key in keys.iter() {
+            removed |= approvals.remove(key).is_some();
         }
+        removed
+    })
+}
+
+pub fn approve_collection(owner: &ICRCAccount, approval: Approval) -> Result<(), ApprovalError> {
+    if *owner == approval.account {
+        return Err(ApprovalError::InvalidSpender);
     }
+
+    let key = OperatorKey {
+        owner: owner.clone(),
+        operator: approval.account.clone(),
+    };
+
+    COLLECTION_APPROVALS.with(|approvals| approvals.borrow_mut().insert(key, approval));
+
+    Ok(())
 }
 
 impl Storable for MintStatus {
+    // raised from 90 with the owner and EVM fields, the map layout allows growing it
     const BOUND: Bound = Bound::Bounded {
         is_fixed_size: false,
-        max_size: 90,
+        max_size: 400,
     };
 
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         std::borrow::Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -336,3 +1103,162 @@ pub fn calc_msgid(caller: &Subaccount, nonce: Nonce) -> u128 {
     }
     id
 }
+
+#[test]
+fn test_approval_expiry_and_operator_key() {
+    let owner = ICRCAccount::new(candid::Principal::from_slice(&[1; 29]), None);
+    let operator = ICRCAccount::new(
+        candid::Principal::from_slice(&[2; 29]),
+        Some(Subaccount([3; 32])),
+    );
+
+    let approval = Approval::new(operator.clone(), Some(100));
+    assert!(approval.is_active(100));
+    assert!(!approval.is_active(101));
+    assert!(Approval::new(operator.clone(), None).is_active(u64::MAX));
+    assert!(approval.to_bytes().len() <= 150);
+
+    let key = OperatorKey { owner, operator };
+    assert_eq!(OperatorKey::from_bytes(key.to_bytes()), key);
+}
+
+#[test]
+fn test_created_at_time_window_and_take_value() {
+    let config = CollectionConfig {
+        tx_window: 100,
+        permitted_drift: 10,
+        default_take_value: Some(5),
+        max_take_value: Some(20),
+        ..Default::default()
+    };
+
+    assert_eq!(created_at_time_check(&config, 1_000, None), Ok(()));
+    assert_eq!(created_at_time_check(&config, 1_000, Some(890)), Ok(()));
+    assert_eq!(created_at_time_check(&config, 1_000, Some(1_010)), Ok(()));
+    assert_eq!(
+        created_at_time_check(&config, 1_000, Some(889)),
+        Err(TimeWindowError::TooOld)
+    );
+    assert_eq!(
+        created_at_time_check(&config, 1_000, Some(1_011)),
+        Err(TimeWindowError::CreatedInFuture { ledger_time: 1_000 })
+    );
+
+    assert_eq!(take_value(&config, None), 5);
+    assert_eq!(take_value(&config, Some(7)), 7);
+    assert_eq!(take_value(&config, Some(50)), 20);
+}
+
+#[test]
+fn test_owner_index_follows_token_owner() {
+    let alice = ICRCAccount::new(Principal::from_slice(&[1; 29]), None);
+    let bob = ICRCAccount::new(Principal::from_slice(&[1; 29]), Some(Subaccount([1; 32])));
+
+    let token = |id: u128, owner: &ICRCAccount| Token {
+        id,
+        owner: owner.clone(),
+        name: format!("Token {}", id),
+        image: None,
+        description: None,
+        approvals: vec![],
+        asset_id: None,
+    };
+
+    for id in [3, 1, 2] {
+        insert_token(token(id, &alice));
+    }
+    insert_token(token(4, &bob));
+
+    assert_eq!(owner_token_ids(&alice, None, usize::MAX), vec![1, 2, 3]);
+    assert_eq!(owner_token_ids(&alice, Some(1), 1), vec![2]);
+    assert_eq!(owner_token_count(&bob), 1);
+
+    insert_token(token(2, &bob));
+    assert_eq!(owner_token_ids(&alice, None, usize::MAX), vec![1, 3]);
+    assert_eq!(owner_token_ids(&bob, None, usize::MAX), vec![2, 4]);
+
+    remove_token(4);
+    assert_eq!(owner_token_ids(&bob, None, usize::MAX), vec![2]);
+    assert_eq!(owner_token_count(&alice), 2);
+}
+
+#[test]
+fn test_dedup_index_prunes_by_created_at_time() {
+    let key = |created_at_time, byte| DedupKey {
+        created_at_time,
+        hash: [byte; 32],
+    };
+
+    insert_dedup_entry(key(300, 1), 0);
+    insert_dedup_entry(key(100, 2), 1);
+    insert_dedup_entry(key(200, 3), 2);
+
+    assert_eq!(DedupKey::from_bytes(key(200, 3).to_bytes()), key(200, 3));
+    assert_eq!(tx_deduplication_check(&key(100, 2)), Some(1));
+    assert_eq!(tx_deduplication_check(&key(100, 3)), None);
+
+    assert_eq!(prune_dedup_index(250), 2);
+    assert_eq!(tx_deduplication_check(&key(100, 2)), None);
+    assert_eq!(tx_deduplication_check(&key(300, 1)), Some(0));
+}
+
+#[test]
+fn test_roles_owner_implies_all() {
+    let admin = Principal::from_slice(&[4; 29]);
+    let owner = Principal::from_slice(&[5; 29]);
+
+    assert!(grant_role(Role::ConfigAdmin, admin));
+    assert!(!grant_role(Role::ConfigAdmin, admin));
+    assert!(grant_role(Role::Owner, owner));
+
+    assert!(has_role(&admin, Role::ConfigAdmin));
+    assert!(!has_role(&admin, Role::Minter));
+    assert!(has_role(&owner, Role::Minter));
+    assert_eq!(role_assignments(Some(Role::ConfigAdmin)).len(), 1);
+
+    assert!(revoke_role(Role::ConfigAdmin, admin));
+    assert!(!has_role(&admin, Role::ConfigAdmin));
+}
+
+#[test]
+fn test_mint_status_fits_bound() {
+    let status = MintStatus {
+        id: u128::MAX,
+        amount: 1,
+        expiry: u64::MAX,
+        state: crate::types::MintState::FundReceived,
+        owner: Some(ICRCAccount::new(
+            candid::Principal::from_slice(&[1; 29]),
+            Some(Subaccount([9; 32])),
+        )),
+        chain_id: Some(u64::MAX),
+        from_block: Some(u64::MAX),
+        to: Some(format!("0x{}", "f".repeat(40))),
+    };
+
+    assert!(status.to_bytes().len() <= MintStatus::BOUND.max_size() as usize);
+}
+
+#[test]
+fn test_pending_mints_index() {
+    use crate::types::MintState;
+
+    let status = |id: u128, state: MintState| MintStatus {
+        id,
+        amount: 1,
+        expiry: 0,
+        state,
+        owner: None,
+        chain_id: None,
+        from_block: None,
+        to: None,
+    };
+
+    index_pending_mint(1, &status(10, MintState::Signed));
+    index_pending_mint(2, &status(20, MintState::Refunding));
+    index_pending_mint(3, &status(30, MintState::Confirmed));
+    assert_eq!(pending_mints(), vec![(1, 10), (2, 20)]);
+
+    index_pending_mint(2, &status(20, MintState::Expired));
+    assert_eq!(pending_mints(), vec![(1, 10)]);
+}
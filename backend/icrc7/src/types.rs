@@ -1,5 +1,6 @@
-use b3_utils::{ledger::ICRCAccount, Subaccount};
-use candid::CandidType;
+use b3_utils::{ledger::ICRCAccount, vec_to_hex_string, Subaccount};
+use candid::{CandidType, Int, Nat, Principal};
+use serde::ser::{SerializeMap, SerializeSeq};
 use serde_bytes::ByteBuf;
 use serde_derive::{Deserialize, Serialize};
 
@@ -21,13 +22,32 @@ pub struct CollectionMetadata {
     pub icrc7_permitted_drift: u64,
 }
 
+/// Administrative roles, canister controllers hold all of them implicitly.
+#[derive(
+    CandidType, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord,
+)]
+pub enum Role {
+    /// Grants and revokes roles.
+    Owner,
+    ConfigAdmin,
+    Minter,
+    Pauser,
+    BridgeOperator,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RoleAssignment {
+    pub role: Role,
+    pub principal: Principal,
+}
+
 #[derive(CandidType)]
 pub struct Standard {
     pub name: String,
     pub url: String,
 }
 
-#[derive(CandidType, Deserialize)]
+#[derive(CandidType, Deserialize, Clone)]
 pub struct TransferArg {
     pub from_subaccount: Option<Subaccount>,
     pub to: ICRCAccount,
@@ -78,6 +98,8 @@ pub enum TransferError {
     GenericBatchError { error_code: u128, message: String },
 }
 
+pub type TransferResult = Result<u128, TransferError>;
+
 #[derive(CandidType, Deserialize)]
 pub struct ApprovalArgs {
     pub from_subaccount: Option<Subaccount>,
@@ -90,27 +112,133 @@ pub struct ApprovalArgs {
 
 #[derive(CandidType, Debug, Clone)]
 pub enum ApprovalError {
+    NonExistingTokenId,
     Unauthorized { tokens_ids: Vec<u128> },
+    InvalidSpender,
     TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    Expired { ledger_time: u64 },
     TemporaryUnavailable,
     GenericError { error_code: u128, msg: String },
 }
 
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BurnArg {
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: u128,
+    pub memo: Option<Memo>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Clone)]
+pub enum BurnError {
+    NonExistingTokenId,
+    Unauthorized,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+pub type BurnResult = Result<u128, BurnError>;
+
+#[derive(CandidType, Debug, Clone)]
+pub enum MintError {
+    SupplyCapReached,
+    TokenIdExists,
+    /// The id was burned while a `burn_account` is set, burned ids are never minted again.
+    TokenIdBurned,
+    InvalidRecipient,
+    Unauthorized,
+    /// `asset_id` does not name a committed asset.
+    AssetNotFound,
+    GenericError {
+        error_code: u128,
+        message: String,
+    },
+    GenericBatchError {
+        error_code: u128,
+        message: String,
+    },
+}
+
+pub type MintResult = Result<u128, MintError>;
+
 #[derive(CandidType, Deserialize)]
 pub struct MintArgs {
-    pub id: u128,
+    /// Next free id when absent.
+    pub id: Option<u128>,
     pub name: String,
     pub description: Option<String>,
+    /// Small images may still be sent inline, they are stored as a single chunk asset.
     pub image: Option<Vec<u8>>,
+    /// Asset uploaded beforehand with `begin_asset_upload`, takes precedence over `image`.
+    pub asset_id: Option<u64>,
     pub to: ICRCAccount,
 }
 
+#[derive(CandidType, Deserialize)]
+pub struct BeginAssetUploadArg {
+    pub content_type: String,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct PutAssetChunkArg {
+    pub asset_id: u64,
+    pub index: u32,
+    pub content: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct CommitAssetUploadArg {
+    pub asset_id: u64,
+    pub chunk_count: u32,
+    /// Sha256 of the concatenated chunks.
+    pub sha256: ByteBuf,
+}
+
+#[derive(CandidType, Debug, Clone, PartialEq, Eq)]
+pub enum AssetError {
+    AssetNotFound,
+    Unauthorized,
+    AlreadyCommitted,
+    ChunkTooLarge { max_chunk_size: u64 },
+    MissingChunk { index: u32 },
+    HashMismatch { sha256: ByteBuf },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct AssetInfo {
+    pub asset_id: u64,
+    pub content_type: String,
+    pub length: u64,
+    pub chunk_count: u32,
+    pub sha256: Option<ByteBuf>,
+}
+
 #[derive(Clone, CandidType, serde::Serialize, serde::Deserialize)]
 pub struct MintStatus {
     pub id: u128,
     pub amount: u128,
     pub expiry: u64,
     pub state: MintState,
+    /// Account the token was taken into custody from, unset on entries of older versions.
+    pub owner: Option<ICRCAccount>,
+    pub chain_id: Option<u64>,
+    /// First EVM block not yet searched for the `SelfMint` log, the finalized block of before
+    /// signing to begin with.
+    pub from_block: Option<u64>,
+    /// Wallet the token is minted to on EVM, kept to sign the request again.
+    pub to: Option<String>,
+}
+
+impl MintStatus {
+    /// Confirmed and expired entries are never looked at again.
+    pub fn is_pending(&self) -> bool {
+        !matches!(self.state, MintState::Confirmed | MintState::Expired)
+    }
 }
 
 #[derive(Clone, CandidType, Deserialize)]
@@ -128,8 +256,396 @@ pub struct SelfMintArgs {
 pub enum MintState {
     #[default]
     Init,
+    /// The token is held by the canister.
     FundReceived,
     Signed,
+    /// `SelfMint(msgid)` was emitted on EVM.
     Confirmed,
+    /// The signature expired without being used and the token was returned.
     Expired,
+    /// The signature expired without being used, the token is held until its refund goes
+    /// through.
+    Refunding,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApprovalInfo {
+    pub spender: ICRCAccount,
+    pub from_subaccount: Option<Subaccount>,
+    pub expires_at: Option<u64>,
+    pub memo: Option<Memo>,
+    pub created_at_time: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveTokenArg {
+    pub token_id: u128,
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Debug, Clone)]
+pub enum ApproveTokenError {
+    InvalidSpender,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ApproveCollectionArg {
+    pub approval_info: ApprovalInfo,
+}
+
+#[derive(CandidType, Debug, Clone)]
+pub enum ApproveCollectionError {
+    InvalidSpender,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RevokeTokenApprovalArg {
+    pub spender: Option<ICRCAccount>,
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: u128,
+    pub memo: Option<Memo>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Clone)]
+pub enum RevokeTokenApprovalError {
+    ApprovalDoesNotExist,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct RevokeCollectionApprovalArg {
+    pub spender: Option<ICRCAccount>,
+    pub from_subaccount: Option<Subaccount>,
+    pub memo: Option<Memo>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Clone)]
+pub enum RevokeCollectionApprovalError {
+    ApprovalDoesNotExist,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct IsApprovedArg {
+    pub spender: ICRCAccount,
+    pub from_subaccount: Option<Subaccount>,
+    pub token_id: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct TokenApproval {
+    pub token_id: u128,
+    pub approval_info: ApprovalInfo,
+}
+
+pub type CollectionApproval = ApprovalInfo;
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TransferFromArg {
+    pub spender_subaccount: Option<Subaccount>,
+    pub from: ICRCAccount,
+    pub to: ICRCAccount,
+    pub token_id: u128,
+    pub memo: Option<Memo>,
+    pub created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Debug, Clone)]
+pub enum TransferFromError {
+    InvalidRecipient,
+    Unauthorized,
+    NonExistingTokenId,
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: u128 },
+    GenericError { error_code: u128, message: String },
+    GenericBatchError { error_code: u128, message: String },
+}
+
+/// Failure of the `created_at_time` window check shared by every update endpoint.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TimeWindowError {
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+}
+
+/// Every error enum with `TooOld` and `CreatedInFuture` variants converts from the window check.
+macro_rules! impl_from_time_window_error {
+    ($($error:ty),* $(,)?) => {$(
+        impl From<TimeWindowError> for $error {
+            fn from(e: TimeWindowError) -> Self {
+                match e {
+                    TimeWindowError::TooOld => Self::TooOld,
+                    TimeWindowError::CreatedInFuture { ledger_time } => {
+                        Self::CreatedInFuture { ledger_time }
+                    }
+                }
+            }
+        }
+    )*};
+}
+
+impl_from_time_window_error!(
+    TransferError,
+    ApprovalError,
+    ApproveTokenError,
+    ApproveCollectionError,
+    RevokeTokenApprovalError,
+    RevokeCollectionApprovalError,
+    BurnError,
+    TransferFromError,
+);
+
+/// ICRC-3 generic value, the representation of every block in the transaction log.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub enum Value {
+    Blob(ByteBuf),
+    Text(String),
+    Nat(Nat),
+    Int(Int),
+    Array(Vec<Value>),
+    Map(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_text(&self) -> Option<&str> {
+        match self {
+            Value::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+
+    pub fn as_nat(&self) -> Option<&Nat> {
+        match self {
+            Value::Nat(nat) => Some(nat),
+            _ => None,
+        }
+    }
+}
+
+// numbers are rendered as decimal strings and blobs as hex, the JSON view is for humans
+impl serde::Serialize for Value {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Blob(blob) => serializer.serialize_str(&vec_to_hex_string(blob)),
+            Value::Text(text) => serializer.serialize_str(text),
+            Value::Nat(nat) => serializer.serialize_str(&nat.0.to_string()),
+            Value::Int(int) => serializer.serialize_str(&int.0.to_string()),
+            Value::Array(values) => {
+                let mut seq = serializer.serialize_seq(Some(values.len()))?;
+                for value in values {
+                    seq.serialize_element(value)?;
+                }
+                seq.end()
+            }
+            Value::Map(entries) => {
+                let mut map = serializer.serialize_map(Some(entries.len()))?;
+                for (key, value) in entries {
+                    map.serialize_entry(key, value)?;
+                }
+                map.end()
+            }
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksArgs {
+    pub start: u128,
+    pub length: u128,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BlockWithId {
+    pub id: u128,
+    pub block: Value,
+}
+
+candid::define_function!(pub GetBlocksCallback : (Vec<GetBlocksArgs>) -> (GetBlocksResult) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ArchivedBlocks {
+    pub args: Vec<GetBlocksArgs>,
+    pub callback: GetBlocksCallback,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct GetBlocksResult {
+    pub log_length: u128,
+    pub blocks: Vec<BlockWithId>,
+    pub archived_blocks: Vec<ArchivedBlocks>,
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct GetArchivesArgs {
+    pub from: Option<Principal>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ICRC3DataCertificate {
+    pub certificate: ByteBuf,
+    pub hash_tree: ByteBuf,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct ICRC3ArchiveInfo {
+    pub canister_id: Principal,
+    pub start: u128,
+    pub end: u128,
+}
+
+/// Archive canister init argument, mirrors `ArchiveConfig` of the archive package.
+#[derive(CandidType, Deserialize)]
+pub struct ArchiveInitArgs {
+    pub ledger_id: Principal,
+    pub start: u64,
+    pub max_blocks: u64,
+}
+
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct ArchiveOptions {
+    /// Number of blocks kept locally before the oldest ones are moved out.
+    pub trigger_threshold: u64,
+    pub num_blocks_to_archive: u64,
+    pub max_blocks_per_archive: u64,
+    /// Defaults to 10T cycles.
+    pub cycles_for_archive_creation: Option<u128>,
+    /// Extra controllers of spawned archives, the ledger is always one.
+    pub controllers: Option<Vec<Principal>>,
+}
+
+/// JSON-RPC endpoint of a chain the CkNFT contract is deployed on.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug)]
+pub struct EvmRpcProvider {
+    pub chain_id: u64,
+    pub url: String,
+}
+
+/// How many providers of a chain have to return the same result for it to be used.
+#[derive(CandidType, Serialize, Deserialize, Clone, Debug, Default)]
+pub enum ConsensusStrategy {
+    /// All of them.
+    #[default]
+    Equality,
+    Threshold {
+        min: u8,
+    },
+}
+
+#[derive(CandidType, Deserialize)]
+pub struct WithdrawArg {
+    pub chain_id: u64,
+    /// Hash of the EVM transaction that called `burnToCkNFT`.
+    pub tx_hash: String,
+}
+
+/// Release of the token burned by one `BurnToCkNFT` log, `Duplicate` once it was released.
+#[derive(CandidType, Debug, Clone)]
+pub struct BurnWithdrawal {
+    pub log_index: u64,
+    pub token_id: u128,
+    pub to: ICRCAccount,
+    pub result: TransferResult,
+}
+
+#[derive(CandidType, Debug, Clone)]
+pub enum WithdrawError {
+    NoRpcProvider {
+        chain_id: u64,
+    },
+    InvalidTransactionHash,
+    TransactionNotFound,
+    TransactionFailed,
+    NotConfirmed {
+        confirmations: u64,
+        required: u64,
+    },
+    NoBurnEvent,
+    RpcError(String),
+    AnonymousCaller,
+    /// Seconds until the caller may submit again without attaching cycles.
+    RateLimited {
+        retry_after: u64,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct SupportedBlockType {
+    pub block_type: String,
+    pub url: String,
+}
+
+/// `http_request` response with the streaming fields of the HTTP gateway interface, the
+/// b3_utils builder only covers the plain ones.
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct HttpResponse {
+    pub status_code: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: ByteBuf,
+    pub streaming_strategy: Option<StreamingStrategy>,
+}
+
+impl From<b3_utils::http::HttpResponse> for HttpResponse {
+    fn from(response: b3_utils::http::HttpResponse) -> Self {
+        Self {
+            status_code: response.status_code,
+            headers: response.headers,
+            body: response.body,
+            streaming_strategy: None,
+        }
+    }
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq, Eq)]
+pub struct StreamingCallbackToken {
+    pub asset_id: u64,
+    /// Index of the next chunk to send.
+    pub index: u32,
+}
+
+candid::define_function!(pub StreamingCallback : (StreamingCallbackToken) -> (StreamingCallbackHttpResponse) query);
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub enum StreamingStrategy {
+    Callback {
+        callback: StreamingCallback,
+        token: StreamingCallbackToken,
+    },
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct StreamingCallbackHttpResponse {
+    pub body: ByteBuf,
+    pub token: Option<StreamingCallbackToken>,
 }